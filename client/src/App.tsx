@@ -1,4 +1,4 @@
-import React, { useEffect, useState } from "react";
+import React, { useEffect, useRef, useState } from "react";
 import {
   AppBar,
   Toolbar,
@@ -15,20 +15,30 @@ import {
   TableCell,
   TableHead,
   Paper,
+  TextField,
+  Button,
 } from "@material-ui/core";
 
 interface Account {
   id: string;
   provider_id: string;
   display_name: string;
+  kind: "account" | "card";
+  account_type: string | null;
+  currency: string | null;
+  iban: string | null;
+  sort_code: string | null;
+  number: string | null;
+  access: "read" | "write" | "owner";
 }
 
 interface Balance {
   currency: string;
   available: number;
   current: number;
-  overdraft: number;
-  update_timestamp: string;
+  overdraft: number | null;
+  fetched_at: string;
+  stale: boolean;
 }
 
 interface Transaction {
@@ -38,18 +48,44 @@ interface Transaction {
   timestamp: string;
 }
 
+interface TransactionPage {
+  transactions: Transaction[];
+  next_cursor: string | null;
+}
+
+class UnauthorizedError extends Error {}
+
 async function fetchAccounts(): Promise<Account[]> {
   const res = await fetch("/api/accounts");
+  if (res.status === 401) {
+    throw new UnauthorizedError();
+  }
   return await res.json();
 }
 
+async function login(username: string, password: string): Promise<boolean> {
+  const res = await fetch("/api/login", {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify({ username, password }),
+  });
+  return res.ok;
+}
+
 async function fetchBalance(account: string): Promise<Balance> {
   const res = await fetch("/api/accounts/" + account + "/balance");
   return await res.json();
 }
 
-async function fetchTransactions(account: string): Promise<Transaction[]> {
-  const res = await fetch("/api/accounts/" + account + "/transactions");
+async function fetchTransactions(
+  account: string,
+  cursor: string | null = null
+): Promise<TransactionPage> {
+  let url = "/api/accounts/" + account + "/transactions";
+  if (cursor) {
+    url += "?cursor=" + encodeURIComponent(cursor);
+  }
+  const res = await fetch(url);
   return await res.json();
 }
 
@@ -74,17 +110,74 @@ function formatMoney(value: number) {
   return value.toFixed(2).replace(/\B(?=(\d{3})+(?!\d))/g, ",");
 }
 
+function Login({ onLogin }: { onLogin: () => void }) {
+  const [username, setUsername] = useState("");
+  const [password, setPassword] = useState("");
+  const [failed, setFailed] = useState(false);
+
+  async function submit(e: React.FormEvent) {
+    e.preventDefault();
+    if (await login(username, password)) {
+      onLogin();
+    } else {
+      setFailed(true);
+    }
+  }
+
+  return (
+    <Container maxWidth="xs">
+      <form onSubmit={submit}>
+        <TextField
+          label="Username"
+          fullWidth
+          margin="normal"
+          value={username}
+          onChange={(e) => setUsername(e.target.value)}
+        />
+        <TextField
+          label="Password"
+          type="password"
+          fullWidth
+          margin="normal"
+          value={password}
+          error={failed}
+          helperText={failed ? "Invalid username or password" : undefined}
+          onChange={(e) => setPassword(e.target.value)}
+        />
+        <Button type="submit" variant="contained" color="primary">
+          Log in
+        </Button>
+      </form>
+    </Container>
+  );
+}
+
 function App() {
   const classes = useStyles();
+  const [loggedIn, setLoggedIn] = useState(true);
   const [accounts, setAccounts] = useState<Account[] | null>(null);
 
   const [activeAccount, setActiveAccount] = useState<Account | null>(null);
   const [balance, setBalance] = useState<Balance | null>(null);
   const [transactions, setTransactions] = useState<Transaction[] | null>(null);
+  const [nextCursor, setNextCursor] = useState<string | null>(null);
+  const [loadingMore, setLoadingMore] = useState(false);
+  // Which account the balance and transactions being shown belong to, so
+  // that responses that arrive after switching to another account can be
+  // ignored.
+  const shownAccount = useRef<string | null>(null);
 
   useEffect(() => {
-    fetchAccounts().then(setAccounts);
-  }, []);
+    if (loggedIn) {
+      fetchAccounts()
+        .then(setAccounts)
+        .catch((e) => {
+          if (e instanceof UnauthorizedError) {
+            setLoggedIn(false);
+          }
+        });
+    }
+  }, [loggedIn]);
 
   useEffect(() => {
     if (accounts && accounts.length > 0) {
@@ -94,11 +187,54 @@ function App() {
 
   useEffect(() => {
     if (activeAccount) {
-      fetchBalance(activeAccount.id).then(setBalance);
-      fetchTransactions(activeAccount.id).then(setTransactions);
+      const account = activeAccount.id;
+      shownAccount.current = account;
+      setNextCursor(null);
+      setLoadingMore(false);
+
+      fetchBalance(account).then((fetched) => {
+        if (shownAccount.current === account) {
+          setBalance(fetched);
+        }
+      });
+      fetchTransactions(account).then((page) => {
+        if (shownAccount.current !== account) {
+          return;
+        }
+        setTransactions(page.transactions);
+        setNextCursor(page.next_cursor);
+      });
     }
   }, [activeAccount]);
 
+  const loadMoreTransactions = () => {
+    // Loading the same page twice would show its transactions twice.
+    if (activeAccount && nextCursor && !loadingMore) {
+      const account = activeAccount.id;
+      setLoadingMore(true);
+
+      fetchTransactions(account, nextCursor).then(
+        (page) => {
+          if (shownAccount.current !== account) {
+            return;
+          }
+          setTransactions((prev) => [...(prev ?? []), ...page.transactions]);
+          setNextCursor(page.next_cursor);
+          setLoadingMore(false);
+        },
+        () => {
+          if (shownAccount.current === account) {
+            setLoadingMore(false);
+          }
+        }
+      );
+    }
+  };
+
+  if (!loggedIn) {
+    return <Login onLogin={() => setLoggedIn(true)} />;
+  }
+
   if (accounts === null) {
     return <p>Loading...</p>;
   }
@@ -137,7 +273,7 @@ function App() {
                       £{formatMoney(balance.current)}
                     </Typography>
                     <Typography className={classes.balanceSecondary}>
-                      £{formatMoney(balance.available - balance.overdraft)}{" "}
+                      £{formatMoney(balance.available - (balance.overdraft ?? 0))}{" "}
                       (inc. pending)
                     </Typography>
                   </>
@@ -180,6 +316,18 @@ function App() {
             </Table>
           </TableContainer>
         )}
+        {nextCursor && (
+          <>
+            <div className={classes.spacer} />
+            <Button
+              variant="outlined"
+              onClick={loadMoreTransactions}
+              disabled={loadingMore}
+            >
+              Load more
+            </Button>
+          </>
+        )}
         <div className={classes.spacer} />
         <div className={classes.spacer} />
       </Container>
@@ -0,0 +1,12 @@
+CREATE TABLE transaction_changes (
+    id             SERIAL PRIMARY KEY,
+    transaction_id TEXT NOT NULL,
+    changed_at     TIMESTAMP NOT NULL,
+    field          TEXT NOT NULL,
+    old_value      TEXT,
+    new_value      TEXT,
+
+    FOREIGN KEY (transaction_id) REFERENCES transactions (id)
+);
+
+CREATE INDEX "transaction_change_transaction_id" ON "transaction_changes" ("transaction_id");
@@ -0,0 +1,3 @@
+ALTER TABLE transactions
+ADD COLUMN status TEXT NOT NULL DEFAULT 'settled',
+ADD COLUMN settled_transaction_id TEXT REFERENCES transactions (id);
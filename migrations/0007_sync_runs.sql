@@ -0,0 +1,15 @@
+CREATE TABLE sync_runs (
+    id          SERIAL PRIMARY KEY,
+    account_id  TEXT NOT NULL,
+    started_at  TIMESTAMP NOT NULL,
+    finished_at TIMESTAMP,
+    status      TEXT NOT NULL,
+    error       TEXT,
+    inserted    INTEGER NOT NULL DEFAULT 0,
+    updated     INTEGER NOT NULL DEFAULT 0,
+    removed     INTEGER NOT NULL DEFAULT 0,
+
+    FOREIGN KEY (account_id) REFERENCES accounts (id)
+);
+
+CREATE INDEX "sync_run_account_started_at" ON "sync_runs" ("account_id", "started_at" DESC);
@@ -0,0 +1,5 @@
+ALTER TABLE sync_runs
+ADD COLUMN queued_at TIMESTAMP,
+ALTER COLUMN started_at DROP NOT NULL;
+
+CREATE UNIQUE INDEX "sync_run_queued_account" ON "sync_runs" ("account_id") WHERE status = 'queued';
@@ -0,0 +1,13 @@
+CREATE TABLE account_balances (
+    id         SERIAL PRIMARY KEY,
+    account_id TEXT NOT NULL,
+    fetched_at TIMESTAMP NOT NULL,
+    currency   TEXT NOT NULL,
+    available  DECIMAL NOT NULL,
+    current    DECIMAL NOT NULL,
+    overdraft  DECIMAL,
+
+    FOREIGN KEY (account_id) REFERENCES accounts (id)
+);
+
+CREATE INDEX "account_balance_account_fetched_at" ON "account_balances" ("account_id", "fetched_at" DESC);
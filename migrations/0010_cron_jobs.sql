@@ -0,0 +1,4 @@
+CREATE TABLE cron_jobs (
+    name     TEXT PRIMARY KEY,
+    last_run TIMESTAMP NOT NULL
+);
@@ -0,0 +1,9 @@
+ALTER TABLE accounts
+ADD COLUMN kind TEXT NOT NULL DEFAULT 'account';
+
+ALTER TABLE account_balances
+ADD COLUMN credit_limit           DECIMAL,
+ADD COLUMN last_statement_balance DECIMAL,
+ADD COLUMN last_statement_date    DATE,
+ADD COLUMN payment_due            DECIMAL,
+ADD COLUMN payment_due_date       DATE;
@@ -0,0 +1,34 @@
+CREATE TABLE direct_debits (
+    id                         TEXT PRIMARY KEY,
+    account_id                 TEXT NOT NULL,
+    timestamp                  TIMESTAMP NOT NULL,
+    name                       TEXT NOT NULL,
+    status                     TEXT NOT NULL,
+    previous_payment_timestamp TIMESTAMP,
+    previous_payment_amount    DECIMAL,
+    currency                   TEXT NOT NULL,
+
+    FOREIGN KEY (account_id) REFERENCES accounts (id)
+);
+
+CREATE TABLE standing_orders (
+    id                   SERIAL PRIMARY KEY,
+    account_id           TEXT NOT NULL,
+    timestamp            TIMESTAMP NOT NULL,
+    frequency            TEXT NOT NULL,
+    status               TEXT,
+    currency             TEXT NOT NULL,
+    reference            TEXT,
+    payee                TEXT,
+    next_payment_date    TIMESTAMP,
+    next_payment_amount  DECIMAL,
+    first_payment_date   TIMESTAMP,
+    first_payment_amount DECIMAL,
+    final_payment_date   TIMESTAMP,
+    final_payment_amount DECIMAL,
+
+    FOREIGN KEY (account_id) REFERENCES accounts (id)
+);
+
+CREATE INDEX "standing_order_account_id" ON "standing_orders" ("account_id");
+CREATE INDEX "direct_debit_account_id" ON "direct_debits" ("account_id");
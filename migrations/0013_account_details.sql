@@ -0,0 +1,6 @@
+ALTER TABLE accounts
+ADD COLUMN account_type TEXT,
+ADD COLUMN currency     TEXT,
+ADD COLUMN iban         TEXT,
+ADD COLUMN sort_code    TEXT,
+ADD COLUMN number       TEXT;
@@ -0,0 +1,5 @@
+ALTER TABLE providers
+ADD COLUMN status TEXT NOT NULL DEFAULT 'connected';
+
+ALTER TABLE accounts
+ADD COLUMN archived_at TIMESTAMP;
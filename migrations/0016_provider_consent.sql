@@ -0,0 +1,3 @@
+ALTER TABLE providers
+ADD COLUMN consent_granted_at TIMESTAMP,
+ADD COLUMN consent_expires_at TIMESTAMP;
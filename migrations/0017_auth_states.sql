@@ -0,0 +1,5 @@
+CREATE TABLE auth_states (
+    nonce         TEXT PRIMARY KEY,
+    code_verifier TEXT NOT NULL,
+    expires_at    TIMESTAMP NOT NULL
+);
@@ -0,0 +1,14 @@
+CREATE TABLE api_tokens (
+    id           SERIAL PRIMARY KEY,
+    user_id      INTEGER NOT NULL,
+    name         TEXT NOT NULL,
+    token_hash   TEXT NOT NULL UNIQUE,
+    scope        TEXT NOT NULL,
+    created_at   TIMESTAMP NOT NULL,
+    last_used_at TIMESTAMP,
+    revoked_at   TIMESTAMP,
+
+    FOREIGN KEY (user_id) REFERENCES users (id)
+);
+
+CREATE INDEX "api_token_user_id" ON "api_tokens" ("user_id");
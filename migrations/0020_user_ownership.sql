@@ -0,0 +1,25 @@
+ALTER TABLE providers
+ADD COLUMN user_id INTEGER REFERENCES users (id);
+
+-- Providers connected before there were users belong to the first user.
+UPDATE providers SET user_id = (SELECT MIN(id) FROM users);
+
+CREATE TABLE account_shares (
+    account_id TEXT NOT NULL,
+    user_id    INTEGER NOT NULL,
+    access     TEXT NOT NULL,
+
+    PRIMARY KEY (account_id, user_id),
+    FOREIGN KEY (account_id) REFERENCES accounts (id),
+    FOREIGN KEY (user_id) REFERENCES users (id)
+);
+
+-- Every account a user can see, either because they connected its provider
+-- or because it has been shared with them.
+CREATE VIEW account_access AS
+SELECT a.id AS account_id, p.user_id, 'owner' AS access
+FROM accounts AS a JOIN providers AS p ON a.provider_id = p.id
+WHERE p.user_id IS NOT NULL
+UNION ALL
+SELECT account_id, user_id, access
+FROM account_shares;
@@ -0,0 +1 @@
+CREATE INDEX "transaction_account_timestamp" ON "transactions" ("account_id", "timestamp" DESC, "id" DESC);
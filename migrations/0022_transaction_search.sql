@@ -0,0 +1,15 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+-- Merchant names are usually the more useful match, so they rank higher.
+ALTER TABLE transactions
+ADD COLUMN search_vector TSVECTOR GENERATED ALWAYS AS (
+    setweight(to_tsvector('english', coalesce(merchant_name, '')), 'A') ||
+    setweight(to_tsvector('english', coalesce(description, '')), 'B')
+) STORED;
+
+CREATE INDEX "transaction_search" ON "transactions" USING GIN ("search_vector");
+
+-- Trigram indexes catch misspellings and partial words that full-text
+-- search misses.
+CREATE INDEX "transaction_description_trgm" ON "transactions" USING GIN ("description" gin_trgm_ops);
+CREATE INDEX "transaction_merchant_name_trgm" ON "transactions" USING GIN ("merchant_name" gin_trgm_ops);
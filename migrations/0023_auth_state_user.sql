@@ -0,0 +1,6 @@
+-- States can only be used by the user that started connecting. Any that are
+-- outstanding can't be tied to a user, so are dropped.
+DELETE FROM auth_states;
+
+ALTER TABLE auth_states
+ADD COLUMN user_id INTEGER NOT NULL REFERENCES users (id);
@@ -0,0 +1,11 @@
+-- Providers were keyed by their TrueLayer id, so a bank could only be
+-- connected by one user. The TrueLayer id is now a separate column, unique
+-- per user, and the id is only used internally. Existing providers keep
+-- their ids so that nothing referencing them changes.
+ALTER TABLE providers ADD COLUMN truelayer_id TEXT;
+
+UPDATE providers SET truelayer_id = id;
+
+ALTER TABLE providers
+ALTER COLUMN truelayer_id SET NOT NULL,
+ADD UNIQUE (user_id, truelayer_id);
@@ -0,0 +1,294 @@
+//! Password hashing, login sessions, API tokens and the middleware that
+//! requires them.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use actix_session::{Session, UserSession};
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    error::{ErrorForbidden, ErrorInternalServerError, ErrorUnauthorized},
+    http::{header, Method},
+    web, Error, HttpMessage,
+};
+use futures::future::{err, ok, Either, FutureExt, LocalBoxFuture, Ready};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+use crate::db::{self, api_tokens::Scope, Db};
+
+const USER_ID_KEY: &str = "user_id";
+
+/// Makes tokens easy to recognise, e.g. by secret scanners.
+const TOKEN_PREFIX: &str = "ft_";
+const TOKEN_LEN: usize = 40;
+
+/// Checked when logging in as a user that doesn't exist, so that it takes as
+/// long as a wrong password. Uses the same parameters as [`hash_password`].
+const DUMMY_HASH: &str =
+    "$argon2i$v=19$m=4096,t=3,p=1$Zq320yC4rbmPBDOWic3PGw$IqFw2t1Q/NHT+2hlaIxJ3SqrRLP2D8u+vW7nBK0C7P4";
+
+/// Hashes a password for saving, using argon2 with a random salt.
+pub fn hash_password(password: &str) -> anyhow::Result<String> {
+    let salt: [u8; 16] = rand::thread_rng().gen();
+    let hash = argon2::hash_encoded(password.as_bytes(), &salt, &argon2::Config::default())?;
+    Ok(hash)
+}
+
+/// Checks a password against a hash created by [`hash_password`].
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    argon2::verify_encoded(hash, password.as_bytes()).unwrap_or(false)
+}
+
+/// Checks a login attempt on the blocking thread pool, since hashing is
+/// slow. `hash` is `None` if there's no such user, in which case the
+/// password is checked against a dummy hash so the timing doesn't reveal
+/// which usernames exist.
+pub async fn check_login(hash: Option<String>, password: String) -> actix_web::Result<bool> {
+    let valid = web::block(move || match hash {
+        Some(hash) => Ok::<_, ()>(verify_password(&hash, &password)),
+        None => {
+            verify_password(DUMMY_HASH, &password);
+            Ok(false)
+        }
+    })
+    .await
+    .map_err(|_| ErrorInternalServerError("failed to check password"))?;
+
+    Ok(valid)
+}
+
+/// Starts a new session for a user.
+pub fn login(session: &Session, user_id: i32) -> actix_web::Result<()> {
+    // Get a new session id, so that a session id set before logging in
+    // can't be reused.
+    session.renew();
+    session.set(USER_ID_KEY, user_id)
+}
+
+pub fn logout(session: &Session) {
+    session.purge();
+}
+
+/// Gets the id of the logged in user, if any.
+pub fn current_user(session: &Session) -> Option<i32> {
+    session.get(USER_ID_KEY).ok().flatten()
+}
+
+/// Generates a new API token, returning it along with the hash to save.
+pub fn generate_token() -> (String, String) {
+    let token = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LEN)
+        .collect::<String>();
+
+    let token = format!("{}{}", TOKEN_PREFIX, token);
+    let hash = hash_token(&token);
+
+    (token, hash)
+}
+
+/// Tokens are long and random, so a fast hash is enough (unlike passwords).
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Who made a request to the API, and what they are allowed to do.
+#[derive(Clone, Copy, Debug)]
+pub struct Identity {
+    pub user_id: i32,
+    /// Logged in users can do anything, while API tokens are limited to
+    /// their scope.
+    pub scope: Scope,
+    /// Set if the request was made with an API token rather than a session.
+    pub via_token: bool,
+}
+
+/// Rejects requests to the connect flow from anyone who isn't logged in.
+/// Everything else (i.e. the web client) is let through, since it has to be
+/// able to show the login page. The API is protected by [`ApiAuth`] instead.
+pub struct RequireLogin;
+
+impl<S, B> Transform<S> for RequireLogin
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequireLoginMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequireLoginMiddleware { service })
+    }
+}
+
+pub struct RequireLoginMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for RequireLoginMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        if !is_protected(req.path()) || current_user(&req.get_session()).is_some() {
+            Either::Left(self.service.call(req))
+        } else {
+            Either::Right(err(ErrorUnauthorized("login required")))
+        }
+    }
+}
+
+fn is_protected(path: &str) -> bool {
+    path == "/connect" || path.starts_with("/connect/")
+}
+
+/// Authenticates API requests with either a login session or an API token
+/// (`Authorization: Bearer ...`), and enforces the token's scope. The
+/// resulting [`Identity`] is available to handlers as an extractor.
+pub struct ApiAuth {
+    /// Paths that can be requested without authenticating, e.g. the login
+    /// endpoint.
+    public: Rc<Vec<String>>,
+}
+
+impl ApiAuth {
+    pub fn new(public: Vec<String>) -> ApiAuth {
+        ApiAuth {
+            public: Rc::new(public),
+        }
+    }
+}
+
+impl<S, B> Transform<S> for ApiAuth
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ApiAuthMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(ApiAuthMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            public: self.public.clone(),
+        })
+    }
+}
+
+pub struct ApiAuthMiddleware<S> {
+    // Shared so that it can be called after the token has been checked.
+    service: Rc<RefCell<S>>,
+    public: Rc<Vec<String>>,
+}
+
+impl<S, B> Service for ApiAuthMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        if self.public.iter().any(|path| path == req.path()) {
+            return self.service.borrow_mut().call(req).boxed_local();
+        }
+
+        let service = self.service.clone();
+
+        async move {
+            let identity = authenticate(&req).await?;
+
+            let safe = req.method() == Method::GET || req.method() == Method::HEAD;
+            if identity.scope == Scope::Read && !safe {
+                return Err(ErrorForbidden("token does not have write access"));
+            }
+
+            req.extensions_mut().insert(identity);
+
+            let res = service.borrow_mut().call(req);
+            res.await
+        }
+        .boxed_local()
+    }
+}
+
+async fn authenticate(req: &ServiceRequest) -> Result<Identity, Error> {
+    if let Some(user_id) = current_user(&req.get_session()) {
+        return Ok(Identity {
+            user_id,
+            scope: Scope::Write,
+            via_token: false,
+        });
+    }
+
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or_else(|| ErrorUnauthorized("login required"))?;
+
+    let db = req.app_data::<Db>().unwrap();
+    let (user_id, scope) = db::api_tokens::authenticate(db, &hash_token(token))
+        .await
+        .map_err(|_| ErrorInternalServerError("failed to check api token"))?
+        .ok_or_else(|| ErrorUnauthorized("invalid api token"))?;
+
+    Ok(Identity {
+        user_id,
+        scope,
+        via_token: true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dummy_hash_matches_current_parameters() {
+        let hash = hash_password("password").unwrap();
+        let params = |hash: &str| hash.rsplitn(3, '$').nth(2).unwrap().to_owned();
+        assert_eq!(params(&hash), params(DUMMY_HASH));
+    }
+
+    #[test]
+    fn verifies_passwords() {
+        let hash = hash_password("password").unwrap();
+        assert!(verify_password(&hash, "password"));
+        assert!(!verify_password(&hash, "Password"));
+        assert!(!verify_password("not a hash", "password"));
+    }
+}
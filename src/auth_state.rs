@@ -0,0 +1,95 @@
+//! Signed, single use `state` values for the TrueLayer connect flow, which
+//! protect the callback against forged and replayed requests.
+//!
+//! A state looks like `{nonce}.{expiry}.{signature}`, where the signature is
+//! an HMAC of the rest using the configured secret key. The nonce is also
+//! saved along with the PKCE code verifier for the request and the user who
+//! made it, and is deleted when the callback uses it. Only that user can use
+//! it, so a state can't be passed on to complete someone else's connection.
+
+use anyhow::anyhow;
+use chrono::{Duration, TimeZone, Utc};
+use hmac::{Hmac, Mac, NewMac};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use sha2::Sha256;
+
+use crate::db::{self, Db};
+
+/// How long the user has to finish connecting a provider.
+const STATE_TTL_MINS: i64 = 10;
+
+const NONCE_LEN: usize = 32;
+
+/// PKCE code verifiers must be between 43 and 128 characters.
+const CODE_VERIFIER_LEN: usize = 64;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub struct Issued {
+    pub state: String,
+    pub code_verifier: String,
+}
+
+/// Issues a new state and PKCE code verifier for a connect request made by
+/// the given user.
+pub async fn issue(db: &Db, secret_key: &[u8], user: i32) -> anyhow::Result<Issued> {
+    let nonce = random_string(NONCE_LEN);
+    let code_verifier = random_string(CODE_VERIFIER_LEN);
+    let expires_at = Utc::now() + Duration::minutes(STATE_TTL_MINS);
+
+    db::auth_states::insert(db, user, &nonce, &code_verifier, expires_at).await?;
+
+    let payload = format!("{}.{}", nonce, expires_at.timestamp());
+    let signature = base64::encode_config(
+        mac(secret_key, &payload).finalize().into_bytes(),
+        base64::URL_SAFE_NO_PAD,
+    );
+
+    Ok(Issued {
+        state: format!("{}.{}", payload, signature),
+        code_verifier,
+    })
+}
+
+/// Checks a state returned to the callback for the given user and marks it
+/// as used, returning the PKCE code verifier it was issued with.
+pub async fn consume(db: &Db, secret_key: &[u8], user: i32, state: &str) -> anyhow::Result<String> {
+    let mut parts = state.rsplitn(2, '.');
+    let signature = parts.next().ok_or_else(|| anyhow!("malformed state"))?;
+    let payload = parts.next().ok_or_else(|| anyhow!("malformed state"))?;
+
+    let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD)?;
+    mac(secret_key, payload)
+        .verify(&signature)
+        .map_err(|_| anyhow!("invalid state signature"))?;
+
+    let mut parts = payload.splitn(2, '.');
+    let nonce = parts.next().ok_or_else(|| anyhow!("malformed state"))?;
+    let expires_at = parts
+        .next()
+        .ok_or_else(|| anyhow!("malformed state"))?
+        .parse()?;
+
+    if Utc.timestamp(expires_at, 0) < Utc::now() {
+        return Err(anyhow!("state has expired"));
+    }
+
+    db::auth_states::take(db, user, nonce)
+        .await?
+        .ok_or_else(|| anyhow!("state has already been used or belongs to another user"))
+}
+
+fn mac(secret_key: &[u8], payload: &str) -> HmacSha256 {
+    // HMAC accepts keys of any length, so this can't fail.
+    let mut mac = HmacSha256::new_varkey(secret_key).unwrap();
+    mac.update(payload.as_bytes());
+    mac
+}
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .collect()
+}
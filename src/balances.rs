@@ -0,0 +1,119 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use chrono::{NaiveDate, Utc};
+
+use crate::db::{
+    self,
+    accounts::{Account, Kind},
+    balances::Balance,
+    Db,
+};
+
+/// Saves a snapshot of the current balance of every account.
+pub async fn snapshot_all(db: &Db, true_layer: &true_layer::Client) -> anyhow::Result<()> {
+    for account in db::accounts::all(db).await? {
+        match fetch(db, true_layer, &account).await {
+            Ok(_) => log::info!("saved balance for account '{}'", account.id),
+            Err(e) => log::error!(
+                "failed to save balance for account '{}': {:#}",
+                account.id,
+                e
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+/// Fetches the live balance of an account from TrueLayer and saves it to the
+/// database.
+pub async fn fetch(
+    db: &Db,
+    true_layer: &true_layer::Client,
+    account: &Account,
+) -> anyhow::Result<Balance> {
+    let balance = match account.kind {
+        Kind::Account => {
+            let balance = true_layer.account_balance(&account.id).await?;
+            Balance {
+                account_id: account.id.clone(),
+                fetched_at: Utc::now(),
+                currency: balance.currency,
+                available: balance.available,
+                current: balance.current,
+                overdraft: balance.overdraft,
+                credit_limit: None,
+                last_statement_balance: None,
+                last_statement_date: None,
+                payment_due: None,
+                payment_due_date: None,
+            }
+        }
+        Kind::Card => {
+            let balance = true_layer.card_balance(&account.id).await?;
+            Balance {
+                account_id: account.id.clone(),
+                fetched_at: Utc::now(),
+                currency: balance.currency,
+                available: balance.available,
+                current: balance.current,
+                overdraft: None,
+                credit_limit: balance.credit_limit,
+                last_statement_balance: balance.last_statement_balance,
+                last_statement_date: balance.last_statement_date.as_deref().and_then(parse_date),
+                payment_due: balance.payment_due,
+                payment_due_date: balance.payment_due_date.as_deref().and_then(parse_date),
+            }
+        }
+    };
+
+    db::balances::insert(db, &balance).await?;
+
+    Ok(balance)
+}
+
+/// Refreshes balances in the background, running at most one refresh per
+/// account at a time.
+#[derive(Clone, Default)]
+pub struct Refresher(Arc<Mutex<HashSet<String>>>);
+
+impl Refresher {
+    /// Starts refreshing the balance of an account, unless a refresh is
+    /// already running for it.
+    pub fn refresh(&self, db: Db, true_layer: Arc<true_layer::Client>, account: Account) {
+        if !self.0.lock().unwrap().insert(account.id.clone()) {
+            log::debug!("balance refresh already running for '{}'", account.id);
+            return;
+        }
+
+        // Removes the account from the running set even if the fetch panics.
+        let running = Running(self.clone(), account.id.clone());
+
+        actix_web::rt::spawn(async move {
+            if let Err(e) = fetch(&db, &true_layer, &account).await {
+                log::error!(
+                    "failed to refresh balance for account '{}': {:#}",
+                    account.id,
+                    e
+                );
+            }
+
+            drop(running);
+        });
+    }
+}
+
+struct Running(Refresher, String);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        (self.0).0.lock().unwrap().remove(&self.1);
+    }
+}
+
+/// Parses the date part of a TrueLayer timestamp, which doesn't always
+/// include a timezone.
+fn parse_date(timestamp: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(timestamp.get(..10)?, "%Y-%m-%d").ok()
+}
@@ -5,6 +5,19 @@ pub struct Config {
     pub http_port: u16,
     pub secret_key: Vec<u8>,
     pub db_url: String,
+    /// Number of days before today that each incremental sync re-fetches, so
+    /// that backdated or late-settling transactions are picked up.
+    pub sync_lookback_days: i64,
+    /// Cron schedules for the background jobs (including seconds).
+    pub sync_schedule: String,
+    pub balance_schedule: String,
+    pub token_refresh_schedule: String,
+    pub payments_schedule: String,
+    /// How old a saved balance can get before it is refreshed from TrueLayer.
+    pub balance_max_age_mins: i64,
+    /// Whether the session cookie is only sent over HTTPS. Turn this off when
+    /// serving over plain HTTP, e.g. in development, or logging in won't work.
+    pub secure_cookies: bool,
 }
 
 impl Config {
@@ -14,6 +27,19 @@ impl Config {
             http_port: var_or_str("FINTRACK_HTTP_PORT", "8000").parse().unwrap(),
             secret_key: env::var("FINTRACK_SECRET_KEY").unwrap().into_bytes(),
             db_url: env::var("DATABASE_URL").unwrap(),
+            sync_lookback_days: var_or_str("FINTRACK_SYNC_LOOKBACK_DAYS", "7")
+                .parse()
+                .unwrap(),
+            sync_schedule: var_or_str("FINTRACK_SYNC_SCHEDULE", "0 0/5 * * * *"),
+            balance_schedule: var_or_str("FINTRACK_BALANCE_SCHEDULE", "0 0 0 * * *"),
+            token_refresh_schedule: var_or_str("FINTRACK_TOKEN_REFRESH_SCHEDULE", "0 0/15 * * * *"),
+            payments_schedule: var_or_str("FINTRACK_PAYMENTS_SCHEDULE", "0 0 0/6 * * *"),
+            balance_max_age_mins: var_or_str("FINTRACK_BALANCE_MAX_AGE_MINS", "60")
+                .parse()
+                .unwrap(),
+            secure_cookies: var_or_str("FINTRACK_SECURE_COOKIES", "true")
+                .parse()
+                .unwrap(),
         }
     }
 }
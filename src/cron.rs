@@ -1,25 +1,120 @@
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
 use std::{future::Future, str::FromStr};
 
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use cron::Schedule;
+use futures::FutureExt;
+use serde::Serialize;
+use tokio::task::JoinHandle;
+
+use crate::db::{self, Db};
+
+/// Keeps track of the schedule and run times of registered jobs.
+#[derive(Clone, Default)]
+pub struct Registry(Arc<Mutex<BTreeMap<String, JobInfo>>>);
+
+#[derive(Clone, Debug, Serialize)]
+pub struct JobInfo {
+    pub name: String,
+    pub schedule: String,
+    pub last_run: Option<DateTime<Utc>>,
+    pub next_run: Option<DateTime<Utc>>,
+}
+
+impl Registry {
+    /// Gets the current state of all registered jobs, ordered by name.
+    pub fn jobs(&self) -> Vec<JobInfo> {
+        self.0.lock().unwrap().values().cloned().collect()
+    }
+
+    fn update(&self, name: &str, f: impl FnOnce(&mut JobInfo)) {
+        if let Some(job) = self.0.lock().unwrap().get_mut(name) {
+            f(job);
+        }
+    }
+}
+
+/// What to do when a run is due while the previous one is still going.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overlap {
+    /// Skip the new run entirely.
+    Skip,
+    /// Wait for the previous run to finish, then start the new one.
+    Queue,
+}
 
 pub struct Builder {
     name: String,
+    expression: String,
     schedule: Schedule,
+    registry: Option<Registry>,
+    overlap: Overlap,
+    db: Option<Db>,
+    catch_up: bool,
 }
 
 pub fn new(name: &str, cron: &str) -> Builder {
     Builder {
         name: name.to_owned(),
+        expression: cron.to_owned(),
         schedule: Schedule::from_str(cron).unwrap(),
+        registry: None,
+        overlap: Overlap::Skip,
+        db: None,
+        catch_up: false,
     }
 }
 
 impl Builder {
+    /// Sets what happens when a run is due while the previous one is still
+    /// going. Defaults to [`Overlap::Skip`].
+    pub fn overlap(mut self, overlap: Overlap) -> Self {
+        self.overlap = overlap;
+        self
+    }
+
+    /// Persists the time of the last successfully completed run to the
+    /// database.
+    pub fn persist(mut self, db: Db) -> Self {
+        self.db = Some(db);
+        self
+    }
+
+    /// Runs the job once on startup if any runs were missed since the last
+    /// persisted run (e.g. while the process was down). Requires
+    /// [`persist`](Builder::persist).
+    pub fn catch_up(mut self) -> Self {
+        self.catch_up = true;
+        self
+    }
+
+    /// Registers the job with a registry, which will be kept up to date as
+    /// the job runs.
+    pub fn registry(mut self, registry: &Registry) -> Self {
+        registry.0.lock().unwrap().insert(
+            self.name.clone(),
+            JobInfo {
+                name: self.name.clone(),
+                schedule: self.expression.clone(),
+                last_run: None,
+                next_run: None,
+            },
+        );
+
+        self.registry = Some(registry.clone());
+        self
+    }
+
     pub fn with_state<S: Clone>(self, state: S) -> StatefulBuilder<S> {
         StatefulBuilder {
             name: self.name,
             schedule: self.schedule,
+            registry: self.registry,
+            overlap: self.overlap,
+            db: self.db,
+            catch_up: self.catch_up,
             state,
         }
     }
@@ -44,6 +139,10 @@ impl Builder {
 pub struct StatefulBuilder<S: Clone> {
     name: String,
     schedule: Schedule,
+    registry: Option<Registry>,
+    overlap: Overlap,
+    db: Option<Db>,
+    catch_up: bool,
     state: S,
 }
 
@@ -61,14 +160,157 @@ impl<S: Clone + Send + 'static> StatefulBuilder<S> {
         R: Future<Output = ()> + Send + 'static,
         F: Fn(S) -> R + Send + 'static,
     {
+        let mut running = None;
+
+        // The state only has to be `Send`, so `self` can't be borrowed across
+        // an await.
+        let db = self.db.clone();
+        let name = self.name.clone();
+
+        if let Some(last_run) = last_run(db.as_ref(), &name).await {
+            self.update(|job| job.last_run = Some(last_run));
+
+            if self.catch_up {
+                if let Some(missed) = latest_missed(&self.schedule, last_run, Utc::now()) {
+                    log::info!("catching up on missed run of '{}'", self.name);
+                    running = Some(self.start(&task, missed));
+                }
+            }
+        }
+
         for next in self.schedule.upcoming(Utc) {
             log::info!("next run of '{}' is scheduled for {}", self.name, next);
+            self.update(|job| job.next_run = Some(next));
+
+            // The scheduled time may already have passed if a queued run
+            // held things up, in which case there's no need to wait.
             let dur = next - Utc::now();
-            let dur = dur.to_std().unwrap();
+            let dur = dur.to_std().unwrap_or_default();
             tokio::time::delay_for(dur).await;
 
-            log::info!("running task '{}'", self.name);
-            tokio::task::spawn((task)(self.state.clone()));
+            if let Some(mut previous) = running.take() {
+                if (&mut previous).now_or_never().is_none() {
+                    match self.overlap {
+                        Overlap::Skip => {
+                            log::warn!("skipping run of '{}', previous run is ongoing", self.name);
+                            running = Some(previous);
+                            continue;
+                        }
+                        Overlap::Queue => {
+                            log::info!("waiting for previous run of '{}' to finish", self.name);
+                            let _ = previous.await;
+                        }
+                    }
+                }
+            }
+
+            running = Some(self.start(&task, next));
         }
     }
+
+    /// Starts a run of the task, returning a handle that completes once the
+    /// run has finished and been recorded.
+    fn start<R, F>(&self, task: &F, scheduled: DateTime<Utc>) -> JoinHandle<()>
+    where
+        R: Future<Output = ()> + Send + 'static,
+        F: Fn(S) -> R + Send + 'static,
+    {
+        log::info!("running task '{}'", self.name);
+        self.update(|job| job.last_run = Some(Utc::now()));
+
+        let handle = tokio::task::spawn((task)(self.state.clone()));
+        let name = self.name.clone();
+        let db = self.db.clone();
+
+        tokio::task::spawn(async move {
+            // Panics are caught by the runtime, so they only need logging. A
+            // run that didn't finish isn't recorded, so it's caught up on.
+            match handle.await {
+                Ok(()) => {
+                    if let Some(db) = db {
+                        if let Err(e) = db::cron_jobs::set_last_run(&db, &name, scheduled).await {
+                            log::error!("failed to save last run of '{}': {}", name, e);
+                        }
+                    }
+                }
+                Err(e) if e.is_panic() => {
+                    let message = panic_message(e.into_panic());
+                    log::error!("task '{}' panicked: {}", name, message);
+                }
+                Err(_) => log::error!("task '{}' was cancelled", name),
+            }
+        })
+    }
+
+    fn update(&self, f: impl FnOnce(&mut JobInfo)) {
+        if let Some(registry) = &self.registry {
+            registry.update(&self.name, f);
+        }
+    }
+}
+
+/// Gets the latest time the job was scheduled to run since its last run, if
+/// any runs have been missed. Catching up counts as that run, so that the
+/// earlier missed runs aren't caught up on again after another restart.
+fn latest_missed(
+    schedule: &Schedule,
+    last_run: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    schedule
+        .after(&last_run)
+        .take_while(|scheduled| *scheduled <= now)
+        .last()
+}
+
+async fn last_run(db: Option<&Db>, name: &str) -> Option<DateTime<Utc>> {
+    match db::cron_jobs::last_run(db?, name).await {
+        Ok(last_run) => last_run,
+        Err(e) => {
+            log::error!("failed to get last run of '{}': {}", name, e);
+            None
+        }
+    }
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown error".to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(hour: u32, min: u32) -> DateTime<Utc> {
+        Utc.ymd(2020, 8, 1).and_hms(hour, min, 0)
+    }
+
+    #[test]
+    fn latest_missed_run() {
+        let schedule = Schedule::from_str("0 0/5 * * * *").unwrap();
+
+        assert_eq!(latest_missed(&schedule, at(12, 0), at(12, 3)), None);
+        assert_eq!(
+            latest_missed(&schedule, at(12, 0), at(12, 5)),
+            Some(at(12, 5))
+        );
+        assert_eq!(
+            latest_missed(&schedule, at(12, 0), at(12, 7)),
+            Some(at(12, 5))
+        );
+
+        // Only the latest of several missed runs is caught up on.
+        assert_eq!(
+            latest_missed(&schedule, at(12, 0), at(13, 2)),
+            Some(at(13, 0))
+        );
+        assert_eq!(latest_missed(&schedule, at(13, 0), at(13, 2)), None);
+    }
 }
@@ -0,0 +1,171 @@
+//! Encryption of secrets (e.g. OAuth tokens) before they are saved to the
+//! database.
+//!
+//! Values are encrypted with ChaCha20-Poly1305, using a key derived from the
+//! configured secret key, and stored as `v2:{base64(nonce || ciphertext)}`.
+//! Each value is bound to a context (e.g. the row and column it is saved in)
+//! through the associated data, so an encrypted value copied somewhere else
+//! won't decrypt.
+//!
+//! Values without a prefix are plaintext, saved before encryption was added,
+//! and are returned as they are so that they can be upgraded. That isn't done
+//! automatically here: callers should check [`Cipher::is_current`] after
+//! decrypting, and save the value again if it isn't.
+
+use anyhow::anyhow;
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use rand::Rng;
+use sha2::Sha256;
+
+const PREFIX: &str = "v2:";
+const NONCE_LEN: usize = 12;
+
+/// Derives a 32 byte key for a particular purpose from the configured secret
+/// key, so that keys used for different things are independent.
+pub fn derive_key(secret_key: &[u8], purpose: &str) -> [u8; 32] {
+    let mut key = [0; 32];
+    // 32 bytes is well within the maximum output length for SHA-256.
+    Hkdf::<Sha256>::new(None, secret_key)
+        .expand(purpose.as_bytes(), &mut key)
+        .unwrap();
+    key
+}
+
+pub struct Cipher(ChaCha20Poly1305);
+
+impl Cipher {
+    pub fn new(secret_key: &[u8]) -> Cipher {
+        let key = derive_key(secret_key, "fintrack token encryption");
+        Cipher(ChaCha20Poly1305::new(GenericArray::from_slice(&key)))
+    }
+
+    /// Encrypts a value, binding it to the given context.
+    pub fn encrypt(&self, plaintext: &str, context: &str) -> String {
+        let mut nonce = [0; NONCE_LEN];
+        rand::thread_rng().fill(&mut nonce);
+
+        let payload = Payload {
+            msg: plaintext.as_bytes(),
+            aad: context.as_bytes(),
+        };
+
+        // Encryption only fails for absurdly large inputs.
+        let ciphertext = self
+            .0
+            .encrypt(GenericArray::from_slice(&nonce), payload)
+            .unwrap();
+
+        let mut value = nonce.to_vec();
+        value.extend(ciphertext);
+
+        format!("{}{}", PREFIX, base64::encode(value))
+    }
+
+    /// Decrypts a value, which must have been encrypted with the same
+    /// context. Plaintext values are returned as they are.
+    pub fn decrypt(&self, value: &str, context: &str) -> anyhow::Result<String> {
+        match value.strip_prefix(PREFIX) {
+            Some(value) => self.open(value, context),
+            None => Ok(value.to_owned()),
+        }
+    }
+
+    /// Returns true if a saved value is encrypted in the current format, so
+    /// doesn't need upgrading.
+    pub fn is_current(value: &str) -> bool {
+        value.starts_with(PREFIX)
+    }
+
+    fn open(&self, value: &str, context: &str) -> anyhow::Result<String> {
+        let value = base64::decode(value)?;
+
+        if value.len() < NONCE_LEN {
+            return Err(anyhow!("encrypted value is too short"));
+        }
+
+        let (nonce, ciphertext) = value.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: context.as_bytes(),
+        };
+
+        let plaintext = self
+            .0
+            .decrypt(GenericArray::from_slice(nonce), payload)
+            .map_err(|_| anyhow!("failed to decrypt value"))?;
+
+        Ok(String::from_utf8(plaintext)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let cipher = Cipher::new(b"secret");
+        let encrypted = cipher.encrypt("token", "context");
+
+        assert!(encrypted.starts_with("v2:"));
+        assert!(!encrypted.contains("token"));
+        assert!(Cipher::is_current(&encrypted));
+        assert_eq!(cipher.decrypt(&encrypted, "context").unwrap(), "token");
+    }
+
+    #[test]
+    fn nonces_are_random() {
+        let cipher = Cipher::new(b"secret");
+        assert_ne!(
+            cipher.encrypt("token", "context"),
+            cipher.encrypt("token", "context")
+        );
+    }
+
+    #[test]
+    fn rejects_tampered_values() {
+        let cipher = Cipher::new(b"secret");
+        let encrypted = cipher.encrypt("token", "context");
+
+        let mut bytes = base64::decode(&encrypted[PREFIX.len()..]).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        let tampered = format!("{}{}", PREFIX, base64::encode(bytes));
+
+        assert!(cipher.decrypt(&tampered, "context").is_err());
+        assert!(cipher.decrypt("v2:AAAA", "context").is_err());
+        assert!(cipher.decrypt("v2:not base64!", "context").is_err());
+    }
+
+    #[test]
+    fn rejects_other_contexts() {
+        let cipher = Cipher::new(b"secret");
+        let encrypted = cipher.encrypt("token", "providers.access_token:a");
+
+        assert!(cipher
+            .decrypt(&encrypted, "providers.access_token:b")
+            .is_err());
+        assert!(cipher
+            .decrypt(&encrypted, "providers.refresh_token:a")
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_other_keys() {
+        let encrypted = Cipher::new(b"secret").encrypt("token", "context");
+        assert!(Cipher::new(b"other")
+            .decrypt(&encrypted, "context")
+            .is_err());
+    }
+
+    #[test]
+    fn reads_plaintext_values() {
+        let cipher = Cipher::new(b"secret");
+
+        assert!(!Cipher::is_current("token"));
+        assert_eq!(cipher.decrypt("token", "context").unwrap(), "token");
+    }
+}
@@ -1,22 +1,45 @@
 pub mod accounts;
+pub mod api_tokens;
+pub mod auth_states;
+pub mod balances;
+pub mod cron_jobs;
+pub mod direct_debits;
 pub mod providers;
+pub mod standing_orders;
+pub mod sync_runs;
 pub mod transactions;
+pub mod users;
+
+use std::sync::Arc;
 
 use sqlx::PgPool;
 
+use crate::crypto::Cipher;
+
 #[derive(Clone)]
-pub struct Db(PgPool);
+pub struct Db {
+    pool: PgPool,
+    /// Used to encrypt provider tokens at rest.
+    cipher: Arc<Cipher>,
+}
 
 impl Db {
-    pub async fn connect(url: &str) -> sqlx::Result<Db> {
-        Ok(Db(PgPool::connect(url).await?))
+    pub async fn connect(url: &str, secret_key: &[u8]) -> sqlx::Result<Db> {
+        Ok(Db {
+            pool: PgPool::connect(url).await?,
+            cipher: Arc::new(Cipher::new(secret_key)),
+        })
     }
 
     pub fn pool(&self) -> &PgPool {
-        &self.0
+        &self.pool
+    }
+
+    pub fn cipher(&self) -> &Cipher {
+        &self.cipher
     }
 
     pub async fn close(self) {
-        self.0.close().await
+        self.pool.close().await
     }
 }
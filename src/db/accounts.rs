@@ -1,5 +1,5 @@
 use chrono::{DateTime, TimeZone, Utc};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use sqlx::postgres::PgRow;
 use sqlx::{Done, Row};
 
@@ -10,16 +10,157 @@ pub struct Account {
     pub id: String,
     pub provider_id: String,
     pub display_name: String,
+    pub kind: Kind,
+    pub account_type: Option<String>,
+    pub currency: Option<String>,
+    pub iban: Option<String>,
+    pub sort_code: Option<String>,
+    /// The account number, or the last few digits of the card number for
+    /// cards.
+    pub number: Option<String>,
+    /// Set when the account's provider is disconnected.
+    pub archived_at: Option<DateTime<Utc>>,
+    /// Set when the provider stops returning the account, e.g. because it
+    /// has been closed at the bank.
+    pub closed_at: Option<DateTime<Utc>>,
 }
 
-/// Gets all accounts from the database.
+/// Distinguishes bank accounts from cards, which are fetched through separate
+/// TrueLayer endpoints.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Account,
+    Card,
+}
+
+impl Kind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Kind::Account => "account",
+            Kind::Card => "card",
+        }
+    }
+
+    fn from_db(value: &str) -> Kind {
+        match value {
+            "card" => Kind::Card,
+            _ => Kind::Account,
+        }
+    }
+}
+
+/// What a user can do with an account. Owners (i.e. the user who connected
+/// the provider) can also share it with others.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    Read,
+    Write,
+    Owner,
+}
+
+impl Access {
+    fn as_str(self) -> &'static str {
+        match self {
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::Owner => "owner",
+        }
+    }
+
+    fn from_db(value: &str) -> Access {
+        match value {
+            "owner" => Access::Owner,
+            "write" => Access::Write,
+            _ => Access::Read,
+        }
+    }
+}
+
+/// An account along with what the requesting user can do with it.
+#[derive(Debug, Serialize)]
+pub struct UserAccount {
+    #[serde(flatten)]
+    pub account: Account,
+    pub access: Access,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Share {
+    pub user_id: i32,
+    pub username: String,
+    pub access: Access,
+}
+
+const COLUMNS: &str = "
+    id, provider_id, display_name, kind,
+    account_type, currency, iban, sort_code, number, archived_at, closed_at
+";
+
+fn from_row(row: PgRow) -> sqlx::Result<Account> {
+    Ok(Account {
+        id: row.get(0),
+        provider_id: row.get(1),
+        display_name: row.get(2),
+        kind: Kind::from_db(row.get(3)),
+        account_type: row.get(4),
+        currency: row.get(5),
+        iban: row.get(6),
+        sort_code: row.get(7),
+        number: row.get(8),
+        archived_at: row
+            .get::<Option<_>, _>(9)
+            .map(|t| Utc.from_utc_datetime(&t)),
+        closed_at: row
+            .get::<Option<_>, _>(10)
+            .map(|t| Utc.from_utc_datetime(&t)),
+    })
+}
+
+/// Gets all accounts from the database across every user, excluding archived
+/// and closed ones. This is for background jobs; requests should only see
+/// the accounts returned by [`for_user`].
 pub async fn all(db: &Db) -> anyhow::Result<Vec<Account>> {
-    let accounts = sqlx::query("SELECT id, provider_id, display_name FROM accounts")
+    let sql = format!(
+        "
+        SELECT {}
+        FROM accounts
+        WHERE archived_at IS NULL AND closed_at IS NULL
+        ORDER BY display_name
+        ",
+        COLUMNS
+    );
+
+    let accounts = sqlx::query(&sql)
+        .try_map(from_row)
+        .fetch_all(db.pool())
+        .await?;
+
+    Ok(accounts)
+}
+
+/// Gets the accounts that a user owns or that have been shared with them,
+/// excluding archived and closed ones.
+pub async fn for_user(db: &Db, user: i32) -> anyhow::Result<Vec<UserAccount>> {
+    let sql = format!(
+        "
+        SELECT {}, x.access
+        FROM accounts JOIN account_access AS x
+        ON x.account_id = accounts.id
+        WHERE x.user_id = $1 AND archived_at IS NULL AND closed_at IS NULL
+        ORDER BY display_name
+        ",
+        COLUMNS
+    );
+
+    let accounts = sqlx::query(&sql)
+        .bind(user)
         .try_map(|row: PgRow| {
-            Ok(Account {
-                id: row.get(0),
-                provider_id: row.get(1),
-                display_name: row.get(2),
+            let access = Access::from_db(row.get(11));
+            Ok(UserAccount {
+                account: from_row(row)?,
+                access,
             })
         })
         .fetch_all(db.pool())
@@ -28,47 +169,199 @@ pub async fn all(db: &Db) -> anyhow::Result<Vec<Account>> {
     Ok(accounts)
 }
 
-/// Inserts a new account into the database.
-///
-/// Returns true if a new row was created, or false otherwise (i.e. an account
-/// with the given id already exists).
-pub async fn insert(db: &Db, id: &str, provider: &str, display_name: &str) -> anyhow::Result<bool> {
+/// Gets what a user can do with an account, or `None` if they can't see it
+/// at all (or it doesn't exist).
+pub async fn access(db: &Db, user: i32, id: &str) -> anyhow::Result<Option<Access>> {
+    let sql = "
+        SELECT access
+        FROM account_access
+        WHERE account_id = $1 AND user_id = $2
+    ";
+
+    let access = sqlx::query(sql)
+        .bind(id)
+        .bind(user)
+        .try_map(|row: PgRow| Ok(Access::from_db(row.get(0))))
+        .fetch_all(db.pool())
+        .await?
+        .into_iter()
+        .max();
+
+    Ok(access)
+}
+
+/// Gets the users that an account has been shared with.
+pub async fn shares(db: &Db, id: &str) -> anyhow::Result<Vec<Share>> {
     let sql = "
-        INSERT INTO accounts (id, provider_id, display_name)
+        SELECT s.user_id, u.username, s.access
+        FROM account_shares AS s JOIN users AS u
+        ON s.user_id = u.id
+        WHERE s.account_id = $1
+        ORDER BY u.username
+    ";
+
+    let shares = sqlx::query(sql)
+        .bind(id)
+        .try_map(|row: PgRow| {
+            Ok(Share {
+                user_id: row.get(0),
+                username: row.get(1),
+                access: Access::from_db(row.get(2)),
+            })
+        })
+        .fetch_all(db.pool())
+        .await?;
+
+    Ok(shares)
+}
+
+/// Shares an account with a user, or changes their access if it is already
+/// shared with them.
+pub async fn share(db: &Db, id: &str, user: i32, access: Access) -> anyhow::Result<()> {
+    let sql = "
+        INSERT INTO account_shares (account_id, user_id, access)
         VALUES ($1, $2, $3)
-        ON CONFLICT DO NOTHING
+        ON CONFLICT (account_id, user_id) DO UPDATE
+        SET access = excluded.access
     ";
 
-    let count = sqlx::query(sql)
+    sqlx::query(sql)
         .bind(id)
-        .bind(provider)
-        .bind(display_name)
+        .bind(user)
+        .bind(access.as_str())
+        .execute(db.pool())
+        .await?;
+
+    Ok(())
+}
+
+/// Stops sharing an account with a user.
+///
+/// Returns false if it wasn't shared with them.
+pub async fn unshare(db: &Db, id: &str, user: i32) -> anyhow::Result<bool> {
+    let count = sqlx::query("DELETE FROM account_shares WHERE account_id = $1 AND user_id = $2")
+        .bind(id)
+        .bind(user)
         .execute(db.pool())
         .await?
         .rows_affected();
 
-    if count > 1 {
-        return Err(anyhow::anyhow!("unexpected inserted row count: {}", count));
-    }
-
     Ok(count == 1)
 }
 
-/// Gets the saved credentials (access_token, expires_at, refresh_token) for
-/// a particular account.
-pub async fn credentials(db: &Db, id: &str) -> anyhow::Result<(String, DateTime<Utc>, String)> {
+/// Gets an account by id.
+pub async fn get(db: &Db, id: &str) -> anyhow::Result<Option<Account>> {
+    let sql = format!("SELECT {} FROM accounts WHERE id = $1", COLUMNS);
+
+    let account = sqlx::query(&sql)
+        .bind(id)
+        .try_map(from_row)
+        .fetch_optional(db.pool())
+        .await?;
+
+    Ok(account)
+}
+
+/// Inserts an account into the database, or refreshes the details of an
+/// existing one. Whether an existing account is archived or closed is left
+/// alone, so that refreshing can't undo disconnecting its provider (see
+/// [`restore`]).
+///
+/// Returns true if a new row was created, or false otherwise (i.e. an account
+/// with the given id already exists). Fails if the id belongs to an account
+/// of a different provider.
+pub async fn upsert(db: &Db, account: &Account) -> anyhow::Result<bool> {
+    let sql = format!(
+        "
+        INSERT INTO accounts ({})
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+        ON CONFLICT (id) DO UPDATE
+        SET display_name = excluded.display_name,
+            kind = excluded.kind,
+            account_type = excluded.account_type,
+            currency = excluded.currency,
+            iban = excluded.iban,
+            sort_code = excluded.sort_code,
+            number = excluded.number
+        WHERE accounts.provider_id = excluded.provider_id
+        RETURNING xmax = 0
+        ",
+        COLUMNS
+    );
+
+    let created = sqlx::query(&sql)
+        .bind(&account.id)
+        .bind(&account.provider_id)
+        .bind(&account.display_name)
+        .bind(account.kind.as_str())
+        .bind(&account.account_type)
+        .bind(&account.currency)
+        .bind(&account.iban)
+        .bind(&account.sort_code)
+        .bind(&account.number)
+        .bind(account.archived_at)
+        .bind(account.closed_at)
+        .try_map(|row: PgRow| Ok(row.get(0)))
+        .fetch_optional(db.pool())
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("account '{}' belongs to another provider", account.id))?;
+
+    Ok(created)
+}
+
+/// Restores the given accounts if they had been archived or closed, e.g.
+/// when their provider is connected again and still returns them. Returns
+/// how many were restored.
+pub async fn restore(db: &Db, ids: &[String]) -> anyhow::Result<u64> {
     let sql = "
-        SELECT access_token, expires_at, refresh_token
-        FROM accounts AS a JOIN providers AS p
-        ON a.provider_id = p.id
-        WHERE a.id = $1
+        UPDATE accounts SET archived_at = NULL, closed_at = NULL
+        WHERE id = ANY($1) AND (archived_at IS NOT NULL OR closed_at IS NOT NULL)
     ";
 
-    let cred = sqlx::query(sql)
-        .bind(id)
-        .try_map(|row: PgRow| Ok((row.get(0), Utc.from_utc_datetime(&row.get(1)), row.get(2))))
-        .fetch_one(db.pool())
+    let count = sqlx::query(sql)
+        .bind(ids)
+        .execute(db.pool())
+        .await?
+        .rows_affected();
+
+    Ok(count)
+}
+
+/// Flags the accounts of a provider with one of the given kinds that aren't
+/// in `ids` as closed, returning the ids of those that weren't already.
+pub async fn close_missing(
+    db: &Db,
+    provider: &str,
+    kinds: &[Kind],
+    ids: &[String],
+) -> anyhow::Result<Vec<String>> {
+    let sql = "
+        UPDATE accounts SET closed_at = $1
+        WHERE provider_id = $2 AND kind = ANY($3) AND id <> ALL($4) AND closed_at IS NULL
+        RETURNING id
+    ";
+
+    let kinds = kinds.iter().map(|k| k.as_str()).collect::<Vec<_>>();
+
+    let closed = sqlx::query(sql)
+        .bind(Utc::now())
+        .bind(provider)
+        .bind(&kinds)
+        .bind(ids)
+        .try_map(|row: PgRow| Ok(row.get(0)))
+        .fetch_all(db.pool())
+        .await?;
+
+    Ok(closed)
+}
+
+/// Gets the ids of the providers that the given accounts belong to.
+pub async fn providers_of(db: &Db, ids: &[String]) -> anyhow::Result<Vec<String>> {
+    let providers = sqlx::query("SELECT DISTINCT provider_id FROM accounts WHERE id = ANY($1)")
+        .bind(ids)
+        .try_map(|row: PgRow| Ok(row.get(0)))
+        .fetch_all(db.pool())
         .await?;
 
-    Ok(cred)
+    Ok(providers)
 }
@@ -0,0 +1,144 @@
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgRow;
+use sqlx::{Done, Row};
+
+use super::Db;
+
+#[derive(Debug, Serialize)]
+pub struct ApiToken {
+    pub id: i32,
+    pub name: String,
+    pub scope: Scope,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+/// What a token is allowed to do. Read tokens can only make safe requests
+/// (i.e. GET), while write tokens can do anything a logged in user can.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Write,
+}
+
+impl Scope {
+    fn as_str(self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+        }
+    }
+
+    fn from_db(value: &str) -> Scope {
+        match value {
+            "write" => Scope::Write,
+            _ => Scope::Read,
+        }
+    }
+}
+
+const COLUMNS: &str = "id, name, scope, created_at, last_used_at";
+
+fn from_row(row: PgRow) -> sqlx::Result<ApiToken> {
+    Ok(ApiToken {
+        id: row.get(0),
+        name: row.get(1),
+        scope: Scope::from_db(row.get(2)),
+        created_at: Utc.from_utc_datetime(&row.get(3)),
+        last_used_at: row
+            .get::<Option<_>, _>(4)
+            .map(|t| Utc.from_utc_datetime(&t)),
+    })
+}
+
+/// Gets a user's tokens, excluding revoked ones.
+pub async fn all(db: &Db, user: i32) -> anyhow::Result<Vec<ApiToken>> {
+    let sql = format!(
+        "
+        SELECT {}
+        FROM api_tokens
+        WHERE user_id = $1 AND revoked_at IS NULL
+        ORDER BY created_at
+        ",
+        COLUMNS
+    );
+
+    let tokens = sqlx::query(&sql)
+        .bind(user)
+        .try_map(from_row)
+        .fetch_all(db.pool())
+        .await?;
+
+    Ok(tokens)
+}
+
+/// Saves a new token for a user. Only the hash of the token is stored.
+pub async fn insert(
+    db: &Db,
+    user: i32,
+    name: &str,
+    token_hash: &str,
+    scope: Scope,
+) -> anyhow::Result<ApiToken> {
+    let sql = format!(
+        "
+        INSERT INTO api_tokens (user_id, name, token_hash, scope, created_at)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING {}
+        ",
+        COLUMNS
+    );
+
+    let token = sqlx::query(&sql)
+        .bind(user)
+        .bind(name)
+        .bind(token_hash)
+        .bind(scope.as_str())
+        .bind(Utc::now())
+        .try_map(from_row)
+        .fetch_one(db.pool())
+        .await?;
+
+    Ok(token)
+}
+
+/// Revokes one of a user's tokens.
+///
+/// Returns false if the token doesn't exist or has already been revoked.
+pub async fn revoke(db: &Db, user: i32, id: i32) -> anyhow::Result<bool> {
+    let sql = "
+        UPDATE api_tokens SET revoked_at = $1
+        WHERE id = $2 AND user_id = $3 AND revoked_at IS NULL
+    ";
+
+    let count = sqlx::query(sql)
+        .bind(Utc::now())
+        .bind(id)
+        .bind(user)
+        .execute(db.pool())
+        .await?
+        .rows_affected();
+
+    Ok(count == 1)
+}
+
+/// Looks up an unrevoked token by its hash and records that it has been
+/// used, returning the (user id, scope) that it grants.
+pub async fn authenticate(db: &Db, token_hash: &str) -> anyhow::Result<Option<(i32, Scope)>> {
+    let sql = "
+        UPDATE api_tokens SET last_used_at = $1
+        WHERE token_hash = $2 AND revoked_at IS NULL
+        RETURNING user_id, scope
+    ";
+
+    let res = sqlx::query(sql)
+        .bind(Utc::now())
+        .bind(token_hash)
+        .try_map(|row: PgRow| Ok((row.get(0), Scope::from_db(row.get(1)))))
+        .fetch_optional(db.pool())
+        .await?;
+
+    Ok(res)
+}
@@ -0,0 +1,60 @@
+use chrono::{DateTime, Utc};
+use sqlx::postgres::PgRow;
+use sqlx::Row;
+
+use super::Db;
+
+/// Saves a connect state newly issued to a user, clearing out any that have
+/// expired.
+pub async fn insert(
+    db: &Db,
+    user: i32,
+    nonce: &str,
+    code_verifier: &str,
+    expires_at: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    let mut tx = db.pool().begin().await?;
+
+    sqlx::query("DELETE FROM auth_states WHERE expires_at < $1")
+        .bind(Utc::now())
+        .execute(&mut tx)
+        .await?;
+
+    let sql = "
+        INSERT INTO auth_states (nonce, user_id, code_verifier, expires_at)
+        VALUES ($1, $2, $3, $4)
+    ";
+
+    sqlx::query(sql)
+        .bind(nonce)
+        .bind(user)
+        .bind(code_verifier)
+        .bind(expires_at)
+        .execute(&mut tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Deletes an unexpired connect state issued to the user, returning its code
+/// verifier. Returns `None` if it doesn't exist, e.g. because it has already
+/// been used or was issued to someone else.
+pub async fn take(db: &Db, user: i32, nonce: &str) -> anyhow::Result<Option<String>> {
+    let sql = "
+        DELETE FROM auth_states
+        WHERE nonce = $1 AND user_id = $2 AND expires_at > $3
+        RETURNING code_verifier
+    ";
+
+    let code_verifier = sqlx::query(sql)
+        .bind(nonce)
+        .bind(user)
+        .bind(Utc::now())
+        .try_map(|row: PgRow| Ok(row.get(0)))
+        .fetch_optional(db.pool())
+        .await?;
+
+    Ok(code_verifier)
+}
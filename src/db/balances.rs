@@ -0,0 +1,157 @@
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgRow;
+use sqlx::Row;
+
+use super::Db;
+
+#[derive(Debug, Serialize)]
+pub struct Balance {
+    pub account_id: String,
+    pub fetched_at: DateTime<Utc>,
+    pub currency: String,
+    pub available: Decimal,
+    pub current: Decimal,
+    pub overdraft: Option<Decimal>,
+    /// The following are only set for cards.
+    pub credit_limit: Option<Decimal>,
+    pub last_statement_balance: Option<Decimal>,
+    pub last_statement_date: Option<NaiveDate>,
+    pub payment_due: Option<Decimal>,
+    pub payment_due_date: Option<NaiveDate>,
+}
+
+const COLUMNS: &str = "
+    account_id, fetched_at, currency, available, current, overdraft,
+    credit_limit, last_statement_balance, last_statement_date,
+    payment_due, payment_due_date
+";
+
+fn from_row(row: &PgRow) -> Balance {
+    Balance {
+        account_id: row.get(0),
+        fetched_at: Utc.from_utc_datetime(&row.get(1)),
+        currency: row.get(2),
+        available: row.get(3),
+        current: row.get(4),
+        overdraft: row.get(5),
+        credit_limit: row.get(6),
+        last_statement_balance: row.get(7),
+        last_statement_date: row.get(8),
+        payment_due: row.get(9),
+        payment_due_date: row.get(10),
+    }
+}
+
+/// Saves a snapshot of an account's balance.
+pub async fn insert(db: &Db, balance: &Balance) -> anyhow::Result<()> {
+    let sql = format!(
+        "
+        INSERT INTO account_balances ({})
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+        ",
+        COLUMNS
+    );
+
+    sqlx::query(&sql)
+        .bind(&balance.account_id)
+        .bind(balance.fetched_at)
+        .bind(&balance.currency)
+        .bind(balance.available)
+        .bind(balance.current)
+        .bind(balance.overdraft)
+        .bind(balance.credit_limit)
+        .bind(balance.last_statement_balance)
+        .bind(balance.last_statement_date)
+        .bind(balance.payment_due)
+        .bind(balance.payment_due_date)
+        .execute(db.pool())
+        .await?;
+
+    Ok(())
+}
+
+/// Gets the most recently saved balance for an account.
+pub async fn latest(db: &Db, account: &str) -> anyhow::Result<Option<Balance>> {
+    let sql = format!(
+        "
+        SELECT {}
+        FROM account_balances
+        WHERE account_id = $1
+        ORDER BY fetched_at DESC
+        LIMIT 1
+        ",
+        COLUMNS
+    );
+
+    let balance = sqlx::query(&sql)
+        .bind(account)
+        .try_map(|row: PgRow| Ok(from_row(&row)))
+        .fetch_optional(db.pool())
+        .await?;
+
+    Ok(balance)
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Interval {
+    Day,
+    Week,
+    Month,
+}
+
+impl Interval {
+    fn as_str(self) -> &'static str {
+        match self {
+            Interval::Day => "day",
+            Interval::Week => "week",
+            Interval::Month => "month",
+        }
+    }
+}
+
+/// The balance of an account at the end of a period.
+#[derive(Debug, Serialize)]
+pub struct HistoryEntry {
+    pub period: DateTime<Utc>,
+    #[serde(flatten)]
+    pub balance: Balance,
+}
+
+/// Gets the history of an account's balance between two timestamps, using
+/// the last snapshot taken in each interval.
+pub async fn history(
+    db: &Db,
+    account: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    interval: Interval,
+) -> anyhow::Result<Vec<HistoryEntry>> {
+    let sql = format!(
+        "
+        SELECT DISTINCT ON (period) {}, date_trunc($2, fetched_at) AS period
+        FROM account_balances
+        WHERE account_id = $1 AND fetched_at >= $3 AND fetched_at < $4
+        ORDER BY period, fetched_at DESC
+        ",
+        COLUMNS
+    );
+
+    let history = sqlx::query(&sql)
+        .bind(account)
+        .bind(interval.as_str())
+        .bind(from)
+        .bind(to)
+        .try_map(|row: PgRow| {
+            Ok(HistoryEntry {
+                period: Utc.from_utc_datetime(&row.get(11)),
+                balance: from_row(&row),
+            })
+        })
+        .fetch_all(db.pool())
+        .await?;
+
+    Ok(history)
+}
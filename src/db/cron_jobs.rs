@@ -0,0 +1,33 @@
+use chrono::{DateTime, TimeZone, Utc};
+use sqlx::postgres::PgRow;
+use sqlx::Row;
+
+use super::Db;
+
+/// Gets the scheduled time of the last completed run of a job.
+pub async fn last_run(db: &Db, name: &str) -> anyhow::Result<Option<DateTime<Utc>>> {
+    let last_run = sqlx::query("SELECT last_run FROM cron_jobs WHERE name = $1")
+        .bind(name)
+        .try_map(|row: PgRow| Ok(Utc.from_utc_datetime(&row.get(0))))
+        .fetch_optional(db.pool())
+        .await?;
+
+    Ok(last_run)
+}
+
+/// Records the scheduled time of the last completed run of a job.
+pub async fn set_last_run(db: &Db, name: &str, last_run: DateTime<Utc>) -> anyhow::Result<()> {
+    let sql = "
+        INSERT INTO cron_jobs (name, last_run)
+        VALUES ($1, $2)
+        ON CONFLICT (name) DO UPDATE SET last_run = EXCLUDED.last_run
+    ";
+
+    sqlx::query(sql)
+        .bind(name)
+        .bind(last_run)
+        .execute(db.pool())
+        .await?;
+
+    Ok(())
+}
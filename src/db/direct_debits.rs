@@ -0,0 +1,87 @@
+use chrono::{DateTime, TimeZone, Utc};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use sqlx::postgres::PgRow;
+use sqlx::Row;
+
+use super::Db;
+
+#[derive(Debug, Serialize)]
+pub struct DirectDebit {
+    pub id: String,
+    pub account_id: String,
+    pub timestamp: DateTime<Utc>,
+    pub name: String,
+    pub status: String,
+    pub previous_payment_timestamp: Option<DateTime<Utc>>,
+    pub previous_payment_amount: Option<Decimal>,
+    pub currency: String,
+}
+
+/// Gets all direct debits for the given account.
+pub async fn all(db: &Db, account: &str) -> anyhow::Result<Vec<DirectDebit>> {
+    let sql = "
+        SELECT id, account_id, timestamp, name, status,
+               previous_payment_timestamp, previous_payment_amount, currency
+        FROM direct_debits
+        WHERE account_id = $1
+        ORDER BY name
+    ";
+
+    let direct_debits = sqlx::query(sql)
+        .bind(account)
+        .try_map(|row: PgRow| {
+            Ok(DirectDebit {
+                id: row.get(0),
+                account_id: row.get(1),
+                timestamp: Utc.from_utc_datetime(&row.get(2)),
+                name: row.get(3),
+                status: row.get(4),
+                previous_payment_timestamp: row
+                    .get::<Option<_>, _>(5)
+                    .map(|t| Utc.from_utc_datetime(&t)),
+                previous_payment_amount: row.get(6),
+                currency: row.get(7),
+            })
+        })
+        .fetch_all(db.pool())
+        .await?;
+
+    Ok(direct_debits)
+}
+
+/// Replaces the saved direct debits for an account with the given ones,
+/// inside a single database transaction.
+pub async fn replace(db: &Db, account: &str, direct_debits: &[DirectDebit]) -> anyhow::Result<()> {
+    let mut tx = db.pool().begin().await?;
+
+    sqlx::query("DELETE FROM direct_debits WHERE account_id = $1")
+        .bind(account)
+        .execute(&mut tx)
+        .await?;
+
+    let sql = "
+        INSERT INTO direct_debits (
+            id, account_id, timestamp, name, status,
+            previous_payment_timestamp, previous_payment_amount, currency
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+    ";
+
+    for dd in direct_debits {
+        sqlx::query(sql)
+            .bind(&dd.id)
+            .bind(account)
+            .bind(dd.timestamp)
+            .bind(&dd.name)
+            .bind(&dd.status)
+            .bind(dd.previous_payment_timestamp)
+            .bind(dd.previous_payment_amount)
+            .bind(&dd.currency)
+            .execute(&mut tx)
+            .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(())
+}
@@ -1,16 +1,121 @@
 use chrono::{DateTime, TimeZone, Utc};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::Serialize;
 use sqlx::postgres::PgRow;
 use sqlx::{Done, Row};
 
 use super::Db;
+use crate::crypto::Cipher;
 
 pub struct Provider {
-    pub id: String,
+    /// The id of the provider on TrueLayer, e.g. `ob-monzo`.
+    pub truelayer_id: String,
+    /// The user who connected the provider.
+    pub user_id: i32,
     pub display_name: String,
     pub logo_url: String,
     pub refresh_token: String,
     pub access_token: String,
     pub expires_at: DateTime<Utc>,
+    pub consent_granted_at: DateTime<Utc>,
+    pub consent_expires_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Connected,
+    Disconnected,
+    /// The consent has expired or been revoked, so the user needs to connect
+    /// the provider again.
+    ReauthRequired,
+}
+
+impl Status {
+    fn as_str(self) -> &'static str {
+        match self {
+            Status::Connected => "connected",
+            Status::Disconnected => "disconnected",
+            Status::ReauthRequired => "reauth_required",
+        }
+    }
+
+    fn from_db(value: &str) -> Status {
+        match value {
+            "disconnected" => Status::Disconnected,
+            "reauth_required" => Status::ReauthRequired,
+            _ => Status::Connected,
+        }
+    }
+}
+
+/// A provider as shown to users, without its credentials.
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    pub id: String,
+    pub truelayer_id: String,
+    pub display_name: String,
+    pub logo_url: String,
+    pub status: Status,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub consent_granted_at: Option<DateTime<Utc>>,
+    pub consent_expires_at: Option<DateTime<Utc>>,
+    /// The number of accounts that haven't been archived.
+    pub account_count: i64,
+}
+
+/// Gets the providers that a user has connected, along with their account
+/// counts.
+pub async fn all(db: &Db, user: i32) -> anyhow::Result<Vec<Summary>> {
+    let sql = "
+        SELECT p.id, p.truelayer_id, p.display_name, p.logo_url, p.status, p.expires_at,
+               p.consent_granted_at, p.consent_expires_at,
+               COUNT(a.id) FILTER (WHERE a.archived_at IS NULL)
+        FROM providers AS p LEFT JOIN accounts AS a
+        ON a.provider_id = p.id
+        WHERE p.user_id = $1
+        GROUP BY p.id
+        ORDER BY p.display_name
+    ";
+
+    let providers = sqlx::query(sql)
+        .bind(user)
+        .try_map(|row: PgRow| {
+            let timestamp = |i: usize| {
+                row.get::<Option<_>, _>(i)
+                    .map(|t| Utc.from_utc_datetime(&t))
+            };
+
+            Ok(Summary {
+                id: row.get(0),
+                truelayer_id: row.get(1),
+                display_name: row.get(2),
+                logo_url: row.get(3),
+                status: Status::from_db(row.get(4)),
+                expires_at: timestamp(5),
+                consent_granted_at: timestamp(6),
+                consent_expires_at: timestamp(7),
+                account_count: row.get(8),
+            })
+        })
+        .fetch_all(db.pool())
+        .await?;
+
+    Ok(providers)
+}
+
+/// Returns true if a provider with the given id exists and was connected by
+/// the given user.
+pub async fn owned_by(db: &Db, id: &str, user: i32) -> anyhow::Result<bool> {
+    let res: Option<i32> = sqlx::query("SELECT 1 FROM providers WHERE id = $1 AND user_id = $2")
+        .bind(id)
+        .bind(user)
+        .try_map(|row: PgRow| Ok(row.get(0)))
+        .fetch_optional(db.pool())
+        .await?;
+
+    Ok(res.is_some())
 }
 
 /// Gets the ids of all providers in the database.
@@ -23,36 +128,131 @@ pub async fn all_ids(db: &Db) -> anyhow::Result<Vec<String>> {
     Ok(providers)
 }
 
-/// Inserts a new provider into the database.
-///
-/// Returns true if a new row was created, or false otherwise (i.e. a provider
-/// with the given id already exists).
-pub async fn insert(db: &Db, provider: &Provider) -> anyhow::Result<bool> {
+/// Gets the ids of all providers whose access token expires before the
+/// given time.
+pub async fn expiring_before(db: &Db, timestamp: DateTime<Utc>) -> anyhow::Result<Vec<String>> {
     let sql = "
-        INSERT INTO providers (id, display_name, logo_url, refresh_token, access_token, expires_at)
-        VALUES ($1, $2, $3, $4, $5, $6)
+        SELECT id FROM providers
+        WHERE expires_at < $1 AND status = 'connected'
     ";
 
-    let count = sqlx::query(sql)
-        .bind(&provider.id)
-        .bind(&provider.display_name)
+    let providers = sqlx::query(sql)
+        .bind(timestamp)
+        .try_map(|row: PgRow| Ok(row.get(0)))
+        .fetch_all(db.pool())
+        .await?;
+
+    Ok(providers)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Upserted {
+    Created,
+    Updated,
+}
+
+/// Inserts a new provider into the database, or updates the credentials and
+/// logo of the user's existing connection to it (e.g. when it is
+/// re-authorised after its consent expired) and marks it as connected again.
+/// Returns the id of the saved provider.
+///
+/// The display name of an existing provider is left alone, since it may have
+/// been changed by the user.
+pub async fn upsert(db: &Db, provider: &Provider) -> anyhow::Result<(String, Upserted)> {
+    let mut tx = db.pool().begin().await?;
+
+    let existing: Option<String> =
+        sqlx::query("SELECT id FROM providers WHERE user_id = $1 AND truelayer_id = $2 FOR UPDATE")
+            .bind(provider.user_id)
+            .bind(&provider.truelayer_id)
+            .try_map(|row: PgRow| Ok(row.get(0)))
+            .fetch_optional(&mut tx)
+            .await?;
+
+    let (id, upserted) = match existing {
+        Some(id) => (id, Upserted::Updated),
+        None => (new_id(&provider.truelayer_id), Upserted::Created),
+    };
+
+    let refresh_token = encrypt(db.cipher(), &id, REFRESH_TOKEN, &provider.refresh_token);
+    let access_token = encrypt(db.cipher(), &id, ACCESS_TOKEN, &provider.access_token);
+
+    let query = match upserted {
+        Upserted::Created => {
+            let sql = "
+                INSERT INTO providers (
+                    id, user_id, truelayer_id, display_name, logo_url, refresh_token,
+                    access_token, expires_at, consent_granted_at, consent_expires_at
+                )
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+            ";
+
+            sqlx::query(sql)
+                .bind(&id)
+                .bind(provider.user_id)
+                .bind(&provider.truelayer_id)
+                .bind(&provider.display_name)
+        }
+        Upserted::Updated => {
+            let sql = "
+                UPDATE providers
+                SET logo_url = $2,
+                    refresh_token = $3,
+                    access_token = $4,
+                    expires_at = $5,
+                    consent_granted_at = $6,
+                    consent_expires_at = $7,
+                    status = 'connected'
+                WHERE id = $1
+            ";
+
+            sqlx::query(sql).bind(&id)
+        }
+    };
+
+    query
         .bind(&provider.logo_url)
-        .bind(&provider.refresh_token)
-        .bind(&provider.access_token)
-        .bind(provider.expires_at.timestamp())
+        .bind(refresh_token)
+        .bind(access_token)
+        .bind(provider.expires_at)
+        .bind(provider.consent_granted_at)
+        .bind(provider.consent_expires_at)
+        .execute(&mut tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok((id, upserted))
+}
+
+/// Generates an id for a new provider. The same TrueLayer provider can be
+/// connected by more than one user, so its id is only used as a readable
+/// prefix.
+fn new_id(truelayer_id: &str) -> String {
+    let suffix = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(12)
+        .collect::<String>();
+
+    format!("{}-{}", truelayer_id, suffix.to_lowercase())
+}
+
+/// Gives any providers without an owner (i.e. connected before there were
+/// users) to the given user, returning how many there were.
+pub async fn claim_unowned(db: &Db, user: i32) -> anyhow::Result<u64> {
+    let count = sqlx::query("UPDATE providers SET user_id = $1 WHERE user_id IS NULL")
+        .bind(user)
         .execute(db.pool())
         .await?
         .rows_affected();
 
-    if count > 1 {
-        return Err(anyhow::anyhow!("unexpected inserted row count: {}", count));
-    }
-
-    Ok(count == 1)
+    Ok(count)
 }
 
 /// Gets the saved credentials (access_token, expires_at, refresh_token) for
-/// a particular provider.
+/// a particular provider, decrypting the tokens.
+///
+/// Tokens that were saved unencrypted are encrypted.
 pub async fn credentials(db: &Db, id: &str) -> anyhow::Result<(String, DateTime<Utc>, String)> {
     let sql = "
         SELECT access_token, expires_at, refresh_token
@@ -60,13 +260,22 @@ pub async fn credentials(db: &Db, id: &str) -> anyhow::Result<(String, DateTime<
         WHERE id = $1
     ";
 
-    let cred = sqlx::query(sql)
-        .bind(id)
-        .try_map(|row: PgRow| Ok((row.get(0), Utc.from_utc_datetime(&row.get(1)), row.get(2))))
-        .fetch_one(db.pool())
-        .await?;
+    let (saved_access_token, expires_at, saved_refresh_token): (String, _, String) =
+        sqlx::query(sql)
+            .bind(id)
+            .try_map(|row: PgRow| Ok((row.get(0), Utc.from_utc_datetime(&row.get(1)), row.get(2))))
+            .fetch_one(db.pool())
+            .await?;
+
+    let access_token = decrypt(db.cipher(), id, ACCESS_TOKEN, &saved_access_token)?;
+    let refresh_token = decrypt(db.cipher(), id, REFRESH_TOKEN, &saved_refresh_token)?;
+
+    if !Cipher::is_current(&saved_access_token) || !Cipher::is_current(&saved_refresh_token) {
+        log::info!("encrypting saved tokens for provider '{}'", id);
+        update_credentials(db, id, &access_token, expires_at, &refresh_token).await?;
+    }
 
-    Ok(cred)
+    Ok((access_token, expires_at, refresh_token))
 }
 
 /// Updates the saved credentials for a particular provider.
@@ -84,9 +293,195 @@ pub async fn update_credentials(
     ";
 
     sqlx::query(sql)
-        .bind(access_token)
+        .bind(encrypt(db.cipher(), id, ACCESS_TOKEN, access_token))
+        .bind(expires_at)
+        .bind(encrypt(db.cipher(), id, REFRESH_TOKEN, refresh_token))
+        .bind(id)
+        .execute(db.pool())
+        .await?;
+
+    Ok(())
+}
+
+/// Re-encrypts the saved tokens of every provider with the current key,
+/// returning the number of providers updated.
+///
+/// Tokens are decrypted with the `old` key, falling back to the current one
+/// so that an interrupted rotation can be run again. Tokens saved unencrypted
+/// are encrypted too, so this can also be run with the same key to encrypt
+/// every provider's tokens at once.
+pub async fn rotate_key(db: &Db, old: &Cipher) -> anyhow::Result<usize> {
+    let mut tx = db.pool().begin().await?;
+
+    let rows: Vec<(String, String, String)> =
+        sqlx::query("SELECT id, access_token, refresh_token FROM providers FOR UPDATE")
+            .try_map(|row: PgRow| Ok((row.get(0), row.get(1), row.get(2))))
+            .fetch_all(&mut tx)
+            .await?;
+
+    let decrypt = |id: &str, column: &str, value: &str| {
+        decrypt(old, id, column, value).or_else(|_| decrypt(db.cipher(), id, column, value))
+    };
+
+    for (id, access_token, refresh_token) in &rows {
+        let access_token = decrypt(id, ACCESS_TOKEN, access_token)?;
+        let refresh_token = decrypt(id, REFRESH_TOKEN, refresh_token)?;
+
+        sqlx::query("UPDATE providers SET access_token = $1, refresh_token = $2 WHERE id = $3")
+            .bind(encrypt(db.cipher(), id, ACCESS_TOKEN, &access_token))
+            .bind(encrypt(db.cipher(), id, REFRESH_TOKEN, &refresh_token))
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(rows.len())
+}
+
+const ACCESS_TOKEN: &str = "access_token";
+const REFRESH_TOKEN: &str = "refresh_token";
+
+/// Encrypts a token for saving in the given column of a provider, so that it
+/// can't be moved to another provider or column.
+fn encrypt(cipher: &Cipher, id: &str, column: &str, token: &str) -> String {
+    cipher.encrypt(token, &format!("providers.{}:{}", column, id))
+}
+
+fn decrypt(cipher: &Cipher, id: &str, column: &str, value: &str) -> anyhow::Result<String> {
+    cipher.decrypt(value, &format!("providers.{}:{}", column, id))
+}
+
+/// Changes the display name of one of a user's providers.
+///
+/// Returns false if the provider doesn't exist or belongs to someone else.
+pub async fn rename(db: &Db, user: i32, id: &str, display_name: &str) -> anyhow::Result<bool> {
+    let sql = "UPDATE providers SET display_name = $1 WHERE id = $2 AND user_id = $3";
+
+    let count = sqlx::query(sql)
+        .bind(display_name)
+        .bind(id)
+        .bind(user)
+        .execute(db.pool())
+        .await?
+        .rows_affected();
+
+    Ok(count == 1)
+}
+
+/// Marks a provider as disconnected and archives its accounts. Their
+/// transactions and balances are kept, but they won't be synced again.
+pub async fn disconnect(db: &Db, id: &str) -> anyhow::Result<()> {
+    let mut tx = db.pool().begin().await?;
+
+    sqlx::query("UPDATE providers SET status = 'disconnected' WHERE id = $1")
+        .bind(id)
+        .execute(&mut tx)
+        .await?;
+
+    sqlx::query(
+        "
+        UPDATE accounts SET archived_at = $1
+        WHERE provider_id = $2 AND archived_at IS NULL
+        ",
+    )
+    .bind(Utc::now())
+    .bind(id)
+    .execute(&mut tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Deletes a provider along with its accounts and everything saved for them.
+pub async fn delete(db: &Db, id: &str) -> anyhow::Result<()> {
+    let accounts = "SELECT id FROM accounts WHERE provider_id = $1";
+    let statements = [
+        format!(
+            "
+            DELETE FROM transaction_changes WHERE transaction_id IN (
+                SELECT id FROM transactions WHERE account_id IN ({})
+            )
+            ",
+            accounts
+        ),
+        format!(
+            "DELETE FROM transactions WHERE account_id IN ({})",
+            accounts
+        ),
+        format!("DELETE FROM sync_runs WHERE account_id IN ({})", accounts),
+        format!(
+            "DELETE FROM account_shares WHERE account_id IN ({})",
+            accounts
+        ),
+        format!(
+            "DELETE FROM account_balances WHERE account_id IN ({})",
+            accounts
+        ),
+        format!(
+            "DELETE FROM direct_debits WHERE account_id IN ({})",
+            accounts
+        ),
+        format!(
+            "DELETE FROM standing_orders WHERE account_id IN ({})",
+            accounts
+        ),
+        "DELETE FROM accounts WHERE provider_id = $1".to_owned(),
+        "DELETE FROM providers WHERE id = $1".to_owned(),
+    ];
+
+    let mut tx = db.pool().begin().await?;
+
+    for sql in &statements {
+        sqlx::query(sql).bind(id).execute(&mut tx).await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Gets the status of a provider.
+pub async fn status(db: &Db, id: &str) -> anyhow::Result<Status> {
+    let status = sqlx::query("SELECT status FROM providers WHERE id = $1")
+        .bind(id)
+        .try_map(|row: PgRow| Ok(Status::from_db(row.get(0))))
+        .fetch_one(db.pool())
+        .await?;
+
+    Ok(status)
+}
+
+/// Updates the status of a provider.
+pub async fn set_status(db: &Db, id: &str, status: Status) -> anyhow::Result<()> {
+    sqlx::query("UPDATE providers SET status = $1 WHERE id = $2")
+        .bind(status.as_str())
+        .bind(id)
+        .execute(db.pool())
+        .await?;
+
+    Ok(())
+}
+
+/// Updates when a provider's consent was granted and when it expires.
+pub async fn update_consent(
+    db: &Db,
+    id: &str,
+    granted_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    let sql = "
+        UPDATE providers
+        SET consent_granted_at = $1, consent_expires_at = $2
+        WHERE id = $3
+    ";
+
+    sqlx::query(sql)
+        .bind(granted_at)
         .bind(expires_at)
-        .bind(refresh_token)
         .bind(id)
         .execute(db.pool())
         .await?;
@@ -0,0 +1,116 @@
+use chrono::{DateTime, TimeZone, Utc};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use sqlx::postgres::PgRow;
+use sqlx::Row;
+
+use super::Db;
+
+#[derive(Debug, Serialize)]
+pub struct StandingOrder {
+    pub account_id: String,
+    pub timestamp: DateTime<Utc>,
+    pub frequency: String,
+    pub status: Option<String>,
+    pub currency: String,
+    pub reference: Option<String>,
+    pub payee: Option<String>,
+    pub next_payment_date: Option<DateTime<Utc>>,
+    pub next_payment_amount: Option<Decimal>,
+    pub first_payment_date: Option<DateTime<Utc>>,
+    pub first_payment_amount: Option<Decimal>,
+    pub final_payment_date: Option<DateTime<Utc>>,
+    pub final_payment_amount: Option<Decimal>,
+}
+
+/// Gets all standing orders for the given account.
+pub async fn all(db: &Db, account: &str) -> anyhow::Result<Vec<StandingOrder>> {
+    let sql = "
+        SELECT account_id, timestamp, frequency, status, currency, reference, payee,
+               next_payment_date, next_payment_amount,
+               first_payment_date, first_payment_amount,
+               final_payment_date, final_payment_amount
+        FROM standing_orders
+        WHERE account_id = $1
+        ORDER BY next_payment_date
+    ";
+
+    let standing_orders = sqlx::query(sql)
+        .bind(account)
+        .try_map(|row: PgRow| {
+            let timestamp = |i: usize| {
+                row.get::<Option<_>, _>(i)
+                    .map(|t| Utc.from_utc_datetime(&t))
+            };
+
+            Ok(StandingOrder {
+                account_id: row.get(0),
+                timestamp: Utc.from_utc_datetime(&row.get(1)),
+                frequency: row.get(2),
+                status: row.get(3),
+                currency: row.get(4),
+                reference: row.get(5),
+                payee: row.get(6),
+                next_payment_date: timestamp(7),
+                next_payment_amount: row.get(8),
+                first_payment_date: timestamp(9),
+                first_payment_amount: row.get(10),
+                final_payment_date: timestamp(11),
+                final_payment_amount: row.get(12),
+            })
+        })
+        .fetch_all(db.pool())
+        .await?;
+
+    Ok(standing_orders)
+}
+
+/// Replaces the saved standing orders for an account with the given ones,
+/// inside a single database transaction.
+///
+/// TrueLayer doesn't give standing orders an id, so there is nothing to
+/// match existing rows against.
+pub async fn replace(
+    db: &Db,
+    account: &str,
+    standing_orders: &[StandingOrder],
+) -> anyhow::Result<()> {
+    let mut tx = db.pool().begin().await?;
+
+    sqlx::query("DELETE FROM standing_orders WHERE account_id = $1")
+        .bind(account)
+        .execute(&mut tx)
+        .await?;
+
+    let sql = "
+        INSERT INTO standing_orders (
+            account_id, timestamp, frequency, status, currency, reference, payee,
+            next_payment_date, next_payment_amount,
+            first_payment_date, first_payment_amount,
+            final_payment_date, final_payment_amount
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+    ";
+
+    for so in standing_orders {
+        sqlx::query(sql)
+            .bind(account)
+            .bind(so.timestamp)
+            .bind(&so.frequency)
+            .bind(&so.status)
+            .bind(&so.currency)
+            .bind(&so.reference)
+            .bind(&so.payee)
+            .bind(so.next_payment_date)
+            .bind(so.next_payment_amount)
+            .bind(so.first_payment_date)
+            .bind(so.first_payment_amount)
+            .bind(so.final_payment_date)
+            .bind(so.final_payment_amount)
+            .execute(&mut tx)
+            .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(())
+}
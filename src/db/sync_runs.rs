@@ -0,0 +1,220 @@
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Serialize;
+use sqlx::postgres::PgRow;
+use sqlx::Row;
+
+use super::transactions::UpsertCounts;
+use super::Db;
+
+#[derive(Debug, Serialize)]
+pub struct SyncRun {
+    pub id: i32,
+    pub account_id: String,
+    pub queued_at: Option<DateTime<Utc>>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub status: Status,
+    pub error: Option<String>,
+    pub inserted: i32,
+    pub updated: i32,
+    pub removed: i32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl Status {
+    fn as_str(self) -> &'static str {
+        match self {
+            Status::Queued => "queued",
+            Status::Running => "running",
+            Status::Succeeded => "succeeded",
+            Status::Failed => "failed",
+        }
+    }
+
+    fn from_db(value: &str) -> Status {
+        match value {
+            "queued" => Status::Queued,
+            "succeeded" => Status::Succeeded,
+            "failed" => Status::Failed,
+            _ => Status::Running,
+        }
+    }
+}
+
+fn from_row(row: PgRow) -> sqlx::Result<SyncRun> {
+    let timestamp = |i: usize| {
+        row.get::<Option<_>, _>(i)
+            .map(|t| Utc.from_utc_datetime(&t))
+    };
+
+    Ok(SyncRun {
+        id: row.get(0),
+        account_id: row.get(1),
+        queued_at: timestamp(2),
+        started_at: timestamp(3),
+        finished_at: timestamp(4),
+        status: Status::from_db(row.get(5)),
+        error: row.get(6),
+        inserted: row.get(7),
+        updated: row.get(8),
+        removed: row.get(9),
+    })
+}
+
+/// Queues a sync run for an account, returning the id of the run.
+///
+/// If there is already a queued run for the account then its id is returned
+/// instead, so that concurrent requests are merged into a single run.
+pub async fn queue(db: &Db, account: &str) -> anyhow::Result<i32> {
+    let sql = "
+        INSERT INTO sync_runs (account_id, queued_at, status)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (account_id) WHERE status = 'queued'
+        DO UPDATE SET queued_at = sync_runs.queued_at
+        RETURNING id
+    ";
+
+    let id = sqlx::query(sql)
+        .bind(account)
+        .bind(Utc::now())
+        .bind(Status::Queued.as_str())
+        .try_map(|row: PgRow| Ok(row.get(0)))
+        .fetch_one(db.pool())
+        .await?;
+
+    Ok(id)
+}
+
+/// Gets the ids of all accounts that have a queued sync run.
+pub async fn queued_accounts(db: &Db) -> anyhow::Result<Vec<String>> {
+    let sql = "SELECT account_id FROM sync_runs WHERE status = 'queued'";
+
+    let accounts = sqlx::query(sql)
+        .try_map(|row: PgRow| Ok(row.get(0)))
+        .fetch_all(db.pool())
+        .await?;
+
+    Ok(accounts)
+}
+
+/// Records the start of a sync run for an account, returning the id of
+/// the run.
+///
+/// Any queued run for the account is picked up and started, otherwise a new
+/// run is created.
+pub async fn start(db: &Db, account: &str) -> anyhow::Result<i32> {
+    let sql = "
+        WITH queued AS (
+            UPDATE sync_runs
+            SET started_at = $2, status = $3
+            WHERE account_id = $1 AND status = 'queued'
+            RETURNING id
+        ), inserted AS (
+            INSERT INTO sync_runs (account_id, started_at, status)
+            SELECT $1, $2, $3
+            WHERE NOT EXISTS (SELECT 1 FROM queued)
+            RETURNING id
+        )
+        SELECT id FROM queued
+        UNION ALL
+        SELECT id FROM inserted
+    ";
+
+    let id = sqlx::query(sql)
+        .bind(account)
+        .bind(Utc::now())
+        .bind(Status::Running.as_str())
+        .try_map(|row: PgRow| Ok(row.get(0)))
+        .fetch_one(db.pool())
+        .await?;
+
+    Ok(id)
+}
+
+/// Marks a sync run as having succeeded, with the given row counts.
+pub async fn succeed(db: &Db, id: i32, counts: &UpsertCounts) -> anyhow::Result<()> {
+    let sql = "
+        UPDATE sync_runs
+        SET finished_at = $1, status = $2, inserted = $3, updated = $4, removed = $5
+        WHERE id = $6
+    ";
+
+    sqlx::query(sql)
+        .bind(Utc::now())
+        .bind(Status::Succeeded.as_str())
+        .bind(counts.inserted as i32)
+        .bind(counts.updated as i32)
+        .bind(counts.removed as i32)
+        .bind(id)
+        .execute(db.pool())
+        .await?;
+
+    Ok(())
+}
+
+/// Marks a sync run as having failed with the given error.
+pub async fn fail(db: &Db, id: i32, error: &str) -> anyhow::Result<()> {
+    let sql = "
+        UPDATE sync_runs
+        SET finished_at = $1, status = $2, error = $3
+        WHERE id = $4
+    ";
+
+    sqlx::query(sql)
+        .bind(Utc::now())
+        .bind(Status::Failed.as_str())
+        .bind(error)
+        .bind(id)
+        .execute(db.pool())
+        .await?;
+
+    Ok(())
+}
+
+/// Gets a sync run by id.
+pub async fn get(db: &Db, user: i32, id: i32) -> anyhow::Result<Option<SyncRun>> {
+    let sql = "
+        SELECT id, account_id, queued_at, started_at, finished_at,
+               status, error, inserted, updated, removed
+        FROM sync_runs
+        WHERE id = $1
+          AND account_id IN (SELECT account_id FROM account_access WHERE user_id = $2)
+    ";
+
+    let run = sqlx::query(sql)
+        .bind(id)
+        .bind(user)
+        .try_map(from_row)
+        .fetch_optional(db.pool())
+        .await?;
+
+    Ok(run)
+}
+
+/// Gets the most recent sync run for each of a user's accounts.
+pub async fn latest(db: &Db, user: i32) -> anyhow::Result<Vec<SyncRun>> {
+    let sql = "
+        SELECT DISTINCT ON (account_id)
+            id, account_id, queued_at, started_at, finished_at,
+            status, error, inserted, updated, removed
+        FROM sync_runs
+        WHERE account_id IN (SELECT account_id FROM account_access WHERE user_id = $1)
+        ORDER BY account_id, id DESC
+    ";
+
+    let runs = sqlx::query(sql)
+        .bind(user)
+        .try_map(from_row)
+        .fetch_all(db.pool())
+        .await?;
+
+    Ok(runs)
+}
@@ -1,11 +1,16 @@
-use chrono::{DateTime, TimeZone, Utc};
+use std::ops::AddAssign;
+
+use anyhow::anyhow;
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
 use rust_decimal::Decimal;
-use sqlx::postgres::PgRow;
-use sqlx::{Done, Row};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::{PgArguments, PgRow};
+use sqlx::query::Query;
+use sqlx::{Done, Postgres, Row};
 
 use super::Db;
 
-#[derive(Debug, serde::Serialize)]
+#[derive(Debug, Serialize)]
 pub struct Transaction {
     pub id: String,
     pub account_id: String,
@@ -16,12 +21,57 @@ pub struct Transaction {
     pub category: Option<String>,
     pub description: Option<String>,
     pub merchant_name: Option<String>,
+    pub status: Status,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pending,
+    Settled,
+}
+
+impl Status {
+    fn as_str(self) -> &'static str {
+        match self {
+            Status::Pending => "pending",
+            Status::Settled => "settled",
+        }
+    }
+
+    fn from_db(value: &str) -> Status {
+        match value {
+            "pending" => Status::Pending,
+            _ => Status::Settled,
+        }
+    }
+}
+
+const COLUMNS: &str = "
+    id, account_id, timestamp, amount, currency,
+    type, category, description, merchant_name, status
+";
+
+fn from_row(row: PgRow) -> sqlx::Result<Transaction> {
+    Ok(Transaction {
+        id: row.get(0),
+        account_id: row.get(1),
+        timestamp: Utc.from_utc_datetime(&row.get(2)),
+        amount: row.get(3),
+        currency: row.get(4),
+        transaction_type: row.get(5),
+        category: row.get(6),
+        description: row.get(7),
+        merchant_name: row.get(8),
+        status: Status::from_db(row.get(9)),
+    })
 }
 
-/// Returns true if there are any recorded transactions
+/// Returns true if there are any recorded settled transactions
 /// for the specified account.
 pub async fn has_any(db: &Db, account: &str) -> anyhow::Result<bool> {
-    let res: Option<i32> = sqlx::query("SELECT 1 FROM transactions WHERE account_id = $1")
+    let sql = "SELECT 1 FROM transactions WHERE account_id = $1 AND status = 'settled'";
+    let res: Option<i32> = sqlx::query(sql)
         .bind(account)
         .try_map(|row: PgRow| Ok(row.get(0)))
         .fetch_optional(db.pool())
@@ -30,107 +80,629 @@ pub async fn has_any(db: &Db, account: &str) -> anyhow::Result<bool> {
     Ok(res.is_some())
 }
 
-/// Returns all transactions for the given account.
-pub async fn all(db: &Db, account: &str) -> anyhow::Result<Vec<Transaction>> {
-    let query = "
-        SELECT id, account_id, timestamp, amount, currency,
-               type, category, description, merchant_name
-        FROM transactions
-        WHERE account_id = $1
-        ORDER BY timestamp DESC
-    ";
+/// Criteria for narrowing down a list of transactions. Every criterion is
+/// optional, and a transaction must match all of the given ones.
+#[derive(Debug, Default, Deserialize)]
+pub struct Filter {
+    /// Inclusive date range.
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    /// Inclusive amount range, where outgoing payments are negative.
+    pub min_amount: Option<Decimal>,
+    pub max_amount: Option<Decimal>,
+    pub category: Option<String>,
+    #[serde(rename = "type")]
+    pub transaction_type: Option<String>,
+    /// Matches any part of the merchant name, ignoring case.
+    pub merchant: Option<String>,
+    /// Matches any part of the description, ignoring case.
+    pub description: Option<String>,
+}
 
-    let transactions = sqlx::query(query)
-        .bind(account)
+/// The start and (exclusive) end of a range of timestamps, either of which
+/// may be open.
+pub type TimeRange = (Option<DateTime<Utc>>, Option<DateTime<Utc>>);
+
+impl Filter {
+    /// Gets the date range as timestamps, where the end is exclusive. Fails
+    /// if the end is out of range.
+    pub fn time_range(&self) -> anyhow::Result<TimeRange> {
+        let from = self.from.map(|d| Utc.from_utc_date(&d).and_hms(0, 0, 0));
+        let to = match self.to {
+            Some(d) => Some(
+                Utc.from_utc_date(&d)
+                    .succ_opt()
+                    .ok_or_else(|| anyhow!("'to' date is out of range"))?
+                    .and_hms(0, 0, 0),
+            ),
+            None => None,
+        };
+
+        Ok((from, to))
+    }
+}
+
+/// Position in a list of transactions ordered newest first, pointing at the
+/// last transaction that was returned.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cursor {
+    pub timestamp: DateTime<Utc>,
+    pub id: String,
+}
+
+impl Cursor {
+    /// Encodes the cursor as an opaque string for use in URLs.
+    pub fn encode(&self) -> String {
+        let value = format!("{}|{}", self.timestamp.to_rfc3339(), self.id);
+        base64::encode_config(value, base64::URL_SAFE_NO_PAD)
+    }
+
+    /// Decodes a cursor previously returned by [`Cursor::encode`].
+    pub fn decode(value: &str) -> anyhow::Result<Cursor> {
+        let value = String::from_utf8(base64::decode_config(value, base64::URL_SAFE_NO_PAD)?)?;
+
+        let mut parts = value.splitn(2, '|');
+        match (parts.next(), parts.next()) {
+            (Some(timestamp), Some(id)) => Ok(Cursor {
+                timestamp: DateTime::parse_from_rfc3339(timestamp)?.with_timezone(&Utc),
+                id: id.to_string(),
+            }),
+            _ => Err(anyhow!("malformed cursor")),
+        }
+    }
+}
+
+/// A transaction along with the names of the account and provider that it
+/// belongs to.
+#[derive(Debug, Serialize)]
+pub struct Entry {
+    #[serde(flatten)]
+    pub transaction: Transaction,
+    pub account_name: String,
+    pub provider_name: String,
+}
+
+/// SQL conditions for a [`Filter`], which expect its values to be bound to
+/// parameters $3 to $10 by [`bind_filter`].
+const FILTER_CONDITIONS: &str = "
+    AND ($3::timestamp IS NULL OR timestamp >= $3)
+    AND ($4::timestamp IS NULL OR timestamp < $4)
+    AND ($5::numeric IS NULL OR amount >= $5)
+    AND ($6::numeric IS NULL OR amount <= $6)
+    AND ($7::text IS NULL OR lower(category) = lower($7))
+    AND ($8::text IS NULL OR lower(type) = lower($8))
+    AND ($9::text IS NULL OR merchant_name ILIKE $9)
+    AND ($10::text IS NULL OR description ILIKE $10)
+";
+
+/// Binds the values of a filter for [`FILTER_CONDITIONS`]. The query must
+/// already have exactly two parameters bound.
+fn bind_filter<'q>(
+    query: Query<'q, Postgres, PgArguments>,
+    filter: &'q Filter,
+) -> anyhow::Result<Query<'q, Postgres, PgArguments>> {
+    let (from, to) = filter.time_range()?;
+
+    Ok(query
+        .bind(from)
+        .bind(to)
+        .bind(filter.min_amount)
+        .bind(filter.max_amount)
+        .bind(&filter.category)
+        .bind(&filter.transaction_type)
+        .bind(filter.merchant.as_deref().map(contains_pattern))
+        .bind(filter.description.as_deref().map(contains_pattern)))
+}
+
+/// A page of transactions, along with the cursor for the next page if
+/// there are any more.
+#[derive(Debug, Serialize)]
+pub struct Page {
+    pub transactions: Vec<Entry>,
+    pub next_cursor: Option<String>,
+}
+
+/// Returns a page of up to `limit` transactions that match the filter,
+/// newest first, starting after the given cursor.
+///
+/// Transactions come from the given accounts, or every account if `None`,
+/// but only ever from accounts that the user can see. Transactions that have
+/// been removed upstream or pending ones that have since settled are
+/// excluded.
+pub async fn page(
+    db: &Db,
+    user: i32,
+    accounts: Option<&[String]>,
+    filter: &Filter,
+    after: Option<&Cursor>,
+    limit: i64,
+) -> anyhow::Result<Page> {
+    let query = format!(
+        "
+        SELECT t.*, a.display_name, p.display_name
+        FROM (
+            SELECT {}
+            FROM transactions
+            WHERE ($1::text[] IS NULL OR account_id = ANY($1))
+              AND removed_at IS NULL AND settled_transaction_id IS NULL
+              AND account_id IN (SELECT account_id FROM account_access WHERE user_id = $2)
+              {}
+              AND ($11::timestamp IS NULL OR (timestamp, id) < ($11, $12))
+            ORDER BY timestamp DESC, id DESC
+            LIMIT $13
+        ) t
+        JOIN accounts a ON a.id = t.account_id
+        JOIN providers p ON p.id = a.provider_id
+        ORDER BY t.timestamp DESC, t.id DESC
+        ",
+        COLUMNS, FILTER_CONDITIONS
+    );
+
+    // Fetch one more than asked for to find out whether there's a next page.
+    let query = sqlx::query(&query).bind(accounts).bind(user);
+    let mut transactions = bind_filter(query, filter)?
+        .bind(after.map(|c| c.timestamp))
+        .bind(after.map(|c| &c.id))
+        .bind(limit + 1)
         .try_map(|row: PgRow| {
-            Ok(Transaction {
-                id: row.get(0),
-                account_id: row.get(1),
-                timestamp: Utc.from_utc_datetime(&row.get(2)),
-                amount: row.get(3),
-                currency: row.get(4),
-                transaction_type: row.get(5),
-                category: row.get(6),
-                description: row.get(7),
-                merchant_name: row.get(8),
+            Ok(Entry {
+                account_name: row.get(10),
+                provider_name: row.get(11),
+                transaction: from_row(row)?,
             })
         })
         .fetch_all(db.pool())
         .await?;
 
-    Ok(transactions)
+    let next_cursor = if transactions.len() as i64 > limit {
+        transactions.truncate(limit as usize);
+        transactions.last().map(|e| {
+            Cursor {
+                timestamp: e.transaction.timestamp,
+                id: e.transaction.id.clone(),
+            }
+            .encode()
+        })
+    } else {
+        None
+    };
+
+    Ok(Page {
+        transactions,
+        next_cursor,
+    })
 }
 
-/// Returns a list of transaction ids for all transactions
+/// Marks the start of a highlighted word in a [`Match`] snippet.
+pub const HIGHLIGHT_START: char = '\u{2}';
+/// Marks the end of a highlighted word in a [`Match`] snippet.
+pub const HIGHLIGHT_END: char = '\u{3}';
+
+/// A transaction that matched a search, with a highlighted snippet of the
+/// text that matched.
+#[derive(Debug, Serialize)]
+pub struct Match {
+    #[serde(flatten)]
+    pub entry: Entry,
+    /// The merchant name and description, with the words that matched
+    /// between [`HIGHLIGHT_START`] and [`HIGHLIGHT_END`]. The text isn't
+    /// escaped, so it must be escaped before these are replaced with markup.
+    pub snippet: String,
+    pub rank: f32,
+}
+
+/// Searches the merchant names and descriptions of every transaction the
+/// user can see, returning up to `limit` of the best matches that also match
+/// the filter.
+///
+/// A transaction matches if it contains any of the words searched for, so
+/// that natural queries find something, and those containing more of them
+/// rank higher. Trigram word similarity also finds misspellings and partial
+/// words.
+pub async fn search(
+    db: &Db,
+    user: i32,
+    text: &str,
+    filter: &Filter,
+    limit: i64,
+) -> anyhow::Result<Vec<Match>> {
+    let query = format!(
+        "
+        SELECT t.*, a.display_name, p.display_name
+        FROM (
+            SELECT {},
+                ts_headline(
+                    'english', concat_ws(' - ', merchant_name, description), query,
+                    'StartSel=' || chr(2) || ', StopSel=' || chr(3)
+                ) AS snippet,
+                ts_rank(search_vector, query) + greatest(
+                    word_similarity($1, coalesce(merchant_name, '')),
+                    word_similarity($1, coalesce(description, ''))
+                ) AS rank
+            FROM transactions, (
+                SELECT replace(plainto_tsquery('english', $1)::text, ' & ', ' | ')::tsquery AS query
+            ) q
+            WHERE (search_vector @@ query OR $1 <% merchant_name OR $1 <% description)
+              AND removed_at IS NULL AND settled_transaction_id IS NULL
+              AND account_id IN (SELECT account_id FROM account_access WHERE user_id = $2)
+              {}
+            ORDER BY rank DESC, timestamp DESC
+            LIMIT $11
+        ) t
+        JOIN accounts a ON a.id = t.account_id
+        JOIN providers p ON p.id = a.provider_id
+        ORDER BY t.rank DESC, t.timestamp DESC
+        ",
+        COLUMNS, FILTER_CONDITIONS
+    );
+
+    let query = sqlx::query(&query).bind(text).bind(user);
+    let matches = bind_filter(query, filter)?
+        .bind(limit)
+        .try_map(|row: PgRow| {
+            Ok(Match {
+                snippet: row.get(10),
+                rank: row.get(11),
+                entry: Entry {
+                    account_name: row.get(12),
+                    provider_name: row.get(13),
+                    transaction: from_row(row)?,
+                },
+            })
+        })
+        .fetch_all(db.pool())
+        .await?;
+
+    Ok(matches)
+}
+
+/// Builds an ILIKE pattern that matches the given text anywhere in a value.
+fn contains_pattern(text: &str) -> String {
+    let escaped = text
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+
+    format!("%{}%", escaped)
+}
+
+/// Returns all settled transactions for the given account that were
 /// made since the specified timestamp.
-pub async fn ids_after(
+pub async fn after(
     db: &Db,
     account: &str,
     timestamp: DateTime<Utc>,
-) -> anyhow::Result<Vec<String>> {
-    let sql = "
-        SELECT id FROM transactions
+) -> anyhow::Result<Vec<Transaction>> {
+    let sql = format!(
+        "
+        SELECT {}
+        FROM transactions
         WHERE account_id = $1 AND timestamp >= $2
-    ";
+          AND status = 'settled' AND removed_at IS NULL
+        ",
+        COLUMNS
+    );
 
-    let transactions = sqlx::query(sql)
+    let transactions = sqlx::query(&sql)
         .bind(account)
         .bind(timestamp)
-        .try_map(|row: PgRow| Ok(row.get(0)))
+        .try_map(from_row)
         .fetch_all(db.pool())
         .await?;
 
     Ok(transactions)
 }
 
-/// Inserts multiple transaction records into the database.
-pub async fn insert_many(db: &Db, transactions: &[Transaction]) -> anyhow::Result<()> {
-    for chunk in transactions.chunks(100) {
-        let mut sql = "
-            INSERT INTO transactions (
-                id, account_id, timestamp, amount, currency,
-                type, category, description, merchant_name
-            ) VALUES
+/// Returns all settled outgoing transactions across every account the user
+/// can see that were made since the specified timestamp, oldest first.
+pub async fn outgoing_after(
+    db: &Db,
+    user: i32,
+    timestamp: DateTime<Utc>,
+) -> anyhow::Result<Vec<Transaction>> {
+    let sql = format!(
         "
-        .to_owned();
-
-        // FIXME: Well this is horrible
-        for i in 0..chunk.len() {
-            sql += " (";
-            for j in 0..9 {
-                sql += "$";
-                itoa::fmt(&mut sql, i * 9 + j + 1)?;
-                if j < 8 {
-                    sql += ", ";
-                }
-            }
-            sql += ")";
-            if i != chunk.len() - 1 {
-                sql += ", ";
-            }
-        }
+        SELECT {}
+        FROM transactions
+        WHERE timestamp >= $1 AND amount < 0
+          AND status = 'settled' AND removed_at IS NULL
+          AND account_id IN (SELECT account_id FROM account_access WHERE user_id = $2)
+        ORDER BY timestamp
+        ",
+        COLUMNS
+    );
 
-        chunk
-            .iter()
-            .fold(sqlx::query(&sql), |query, t| {
-                query
-                    .bind(&t.id)
-                    .bind(&t.account_id)
-                    .bind(t.timestamp)
-                    .bind(t.amount)
-                    .bind(&t.currency)
-                    .bind(&t.transaction_type)
-                    .bind(&t.category)
-                    .bind(&t.description)
-                    .bind(&t.merchant_name)
-            })
-            .execute(db.pool())
+    let transactions = sqlx::query(&sql)
+        .bind(timestamp)
+        .bind(user)
+        .try_map(from_row)
+        .fetch_all(db.pool())
+        .await?;
+
+    Ok(transactions)
+}
+
+/// Returns the pending transactions for the given account that have not yet
+/// been matched to a settled transaction.
+pub async fn unmatched_pending(db: &Db, account: &str) -> anyhow::Result<Vec<Transaction>> {
+    let sql = format!(
+        "
+        SELECT {}
+        FROM transactions
+        WHERE account_id = $1 AND status = 'pending'
+          AND removed_at IS NULL AND settled_transaction_id IS NULL
+        ",
+        COLUMNS
+    );
+
+    let transactions = sqlx::query(&sql)
+        .bind(account)
+        .try_map(from_row)
+        .fetch_all(db.pool())
+        .await?;
+
+    Ok(transactions)
+}
+
+/// Returns the ids of settled transactions for the given account that have
+/// already been matched to a pending transaction.
+pub async fn matched_settled_ids(db: &Db, account: &str) -> anyhow::Result<Vec<String>> {
+    let sql = "
+        SELECT settled_transaction_id FROM transactions
+        WHERE account_id = $1 AND settled_transaction_id IS NOT NULL
+    ";
+
+    let ids = sqlx::query(sql)
+        .bind(account)
+        .try_map(|row: PgRow| Ok(row.get(0)))
+        .fetch_all(db.pool())
+        .await?;
+
+    Ok(ids)
+}
+
+/// Links pending transactions to the settled transactions that replaced
+/// them, given as (pending id, settled id) pairs.
+pub async fn mark_settled(db: &Db, matches: &[(String, String)]) -> anyhow::Result<()> {
+    let sql = "
+        UPDATE transactions
+        SET settled_transaction_id = $1
+        WHERE id = $2 AND status = 'pending'
+    ";
+
+    let mut tx = db.pool().begin().await?;
+
+    for (pending, settled) in matches {
+        sqlx::query(sql)
+            .bind(settled)
+            .bind(pending)
+            .execute(&mut tx)
             .await?;
     }
 
+    tx.commit().await?;
+
     Ok(())
 }
 
+/// A change to a single field of an existing transaction.
+#[derive(Debug)]
+pub struct Change {
+    pub transaction_id: String,
+    pub field: String,
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+}
+
+/// Counts of the rows affected by [`upsert_after`].
+#[derive(Debug, Default)]
+pub struct UpsertCounts {
+    pub inserted: u64,
+    pub updated: u64,
+    pub removed: u64,
+}
+
+impl AddAssign for UpsertCounts {
+    fn add_assign(&mut self, other: UpsertCounts) {
+        self.inserted += other.inserted;
+        self.updated += other.updated;
+        self.removed += other.removed;
+    }
+}
+
+/// Saves the transactions fetched for an account since the given timestamp.
+///
+/// New transactions are inserted and existing ones are updated in place. Any
+/// saved transactions since `timestamp` that are missing from `transactions`
+/// are marked as removed rather than deleted, and the given field-level
+/// `changes` are recorded in the change history. Everything runs inside a
+/// single database transaction, so a failure part way through leaves the
+/// saved history untouched.
+pub async fn upsert_after(
+    db: &Db,
+    account: &str,
+    timestamp: DateTime<Utc>,
+    transactions: &[Transaction],
+    changes: &[Change],
+) -> anyhow::Result<UpsertCounts> {
+    let mut tx = db.pool().begin().await?;
+    let mut counts = UpsertCounts::default();
+    let now = Utc::now();
+
+    // Record the history first, since the upsert below overwrites the
+    // previous values.
+    for change in changes {
+        let sql = "
+            INSERT INTO transaction_changes (
+                transaction_id, changed_at, field, old_value, new_value
+            ) VALUES ($1, $2, $3, $4, $5)
+        ";
+
+        sqlx::query(sql)
+            .bind(&change.transaction_id)
+            .bind(now)
+            .bind(&change.field)
+            .bind(&change.old_value)
+            .bind(&change.new_value)
+            .execute(&mut tx)
+            .await?;
+    }
+
+    for chunk in transactions.chunks(100) {
+        let (inserted, updated) = upsert_chunk(&mut tx, chunk).await?;
+        counts.inserted += inserted;
+        counts.updated += updated;
+    }
+
+    let sql = "
+        UPDATE transactions
+        SET removed_at = $1
+        WHERE account_id = $2 AND timestamp >= $3
+          AND status = 'settled' AND removed_at IS NULL AND id <> ALL($4)
+    ";
+
+    let ids = transactions
+        .iter()
+        .map(|t| t.id.clone())
+        .collect::<Vec<_>>();
+
+    counts.removed = sqlx::query(sql)
+        .bind(now)
+        .bind(account)
+        .bind(timestamp)
+        .bind(&ids)
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+
+    tx.commit().await?;
+
+    Ok(counts)
+}
+
+/// Saves the current list of pending transactions for an account.
+///
+/// Pending transactions that have disappeared upstream without being matched
+/// to a settled transaction (e.g. cancelled card authorisations) are marked
+/// as removed.
+pub async fn upsert_pending(
+    db: &Db,
+    account: &str,
+    transactions: &[Transaction],
+) -> anyhow::Result<UpsertCounts> {
+    let mut tx = db.pool().begin().await?;
+    let mut counts = UpsertCounts::default();
+
+    for chunk in transactions.chunks(100) {
+        let (inserted, updated) = upsert_chunk(&mut tx, chunk).await?;
+        counts.inserted += inserted;
+        counts.updated += updated;
+    }
+
+    let sql = "
+        UPDATE transactions
+        SET removed_at = $1
+        WHERE account_id = $2 AND status = 'pending' AND removed_at IS NULL
+          AND settled_transaction_id IS NULL AND id <> ALL($3)
+    ";
+
+    let ids = transactions
+        .iter()
+        .map(|t| t.id.clone())
+        .collect::<Vec<_>>();
+
+    counts.removed = sqlx::query(sql)
+        .bind(Utc::now())
+        .bind(account)
+        .bind(&ids)
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+
+    tx.commit().await?;
+
+    Ok(counts)
+}
+
+/// Inserts or updates a chunk of transactions, returning the number of rows
+/// that were (inserted, updated).
+async fn upsert_chunk(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    chunk: &[Transaction],
+) -> anyhow::Result<(u64, u64)> {
+    let mut sql = format!("INSERT INTO transactions ({}) VALUES", COLUMNS);
+
+    // FIXME: Well this is horrible
+    for i in 0..chunk.len() {
+        sql += " (";
+        for j in 0..10 {
+            sql += "$";
+            itoa::fmt(&mut sql, i * 10 + j + 1)?;
+            if j < 9 {
+                sql += ", ";
+            }
+        }
+        sql += ")";
+        if i != chunk.len() - 1 {
+            sql += ", ";
+        }
+    }
+
+    // Rows that haven't changed are left alone, so only genuine updates
+    // are returned. `xmax` is zero for freshly inserted rows. A settled
+    // transaction is never downgraded back to pending.
+    sql += "
+        ON CONFLICT (id) DO UPDATE SET
+            account_id = EXCLUDED.account_id,
+            timestamp = EXCLUDED.timestamp,
+            amount = EXCLUDED.amount,
+            currency = EXCLUDED.currency,
+            type = EXCLUDED.type,
+            category = EXCLUDED.category,
+            description = EXCLUDED.description,
+            merchant_name = EXCLUDED.merchant_name,
+            status = EXCLUDED.status,
+            removed_at = NULL
+        WHERE NOT (transactions.status = 'settled' AND EXCLUDED.status = 'pending')
+          AND (
+              transactions.removed_at IS NOT NULL
+              OR (
+                  transactions.account_id, transactions.timestamp, transactions.amount,
+                  transactions.currency, transactions.type, transactions.category,
+                  transactions.description, transactions.merchant_name, transactions.status
+              ) IS DISTINCT FROM (
+                  EXCLUDED.account_id, EXCLUDED.timestamp, EXCLUDED.amount,
+                  EXCLUDED.currency, EXCLUDED.type, EXCLUDED.category,
+                  EXCLUDED.description, EXCLUDED.merchant_name, EXCLUDED.status
+              )
+          )
+        RETURNING xmax = 0
+    ";
+
+    let inserted: Vec<bool> = chunk
+        .iter()
+        .fold(sqlx::query(&sql), |query, t| {
+            query
+                .bind(&t.id)
+                .bind(&t.account_id)
+                .bind(t.timestamp)
+                .bind(t.amount)
+                .bind(&t.currency)
+                .bind(&t.transaction_type)
+                .bind(&t.category)
+                .bind(&t.description)
+                .bind(&t.merchant_name)
+                .bind(t.status.as_str())
+        })
+        .try_map(|row: PgRow| Ok(row.get(0)))
+        .fetch_all(&mut *tx)
+        .await?;
+
+    let count = inserted.iter().filter(|inserted| **inserted).count() as u64;
+
+    Ok((count, inserted.len() as u64 - count))
+}
+
 /// Deletes ***all*** transactions from the database.
 pub async fn delete_all(db: &Db) -> anyhow::Result<()> {
     sqlx::query("DELETE FROM transactions")
@@ -142,22 +714,64 @@ pub async fn delete_all(db: &Db) -> anyhow::Result<()> {
     Ok(())
 }
 
-/// Deletes all transactions for the specified account that were
-/// made since the given timestamp.
-pub async fn delete_after(db: &Db, account: &str, timestamp: DateTime<Utc>) -> anyhow::Result<()> {
-    let sql = "
-        DELETE FROM transactions
-        WHERE account_id = $1 AND timestamp >= $2
-    ";
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let count = sqlx::query(sql)
-        .bind(account)
-        .bind(timestamp.date().and_hms(0, 0, 0))
-        .execute(db.pool())
-        .await?
-        .rows_affected();
+    #[test]
+    fn cursor_round_trip() {
+        let cursor = Cursor {
+            timestamp: Utc.ymd(2020, 8, 1).and_hms_milli(12, 30, 15, 250),
+            id: "abc|def".to_owned(),
+        };
 
-    log::info!("{} transactions deleted from db", count);
+        let encoded = cursor.encode();
+        assert!(!encoded.contains('|'));
+        assert_eq!(Cursor::decode(&encoded).unwrap(), cursor);
+    }
 
-    Ok(())
+    #[test]
+    fn malformed_cursors() {
+        let encode = |value: &str| base64::encode_config(value, base64::URL_SAFE_NO_PAD);
+
+        assert!(Cursor::decode("not base64!").is_err());
+        assert!(Cursor::decode(&encode("2020-08-01T12:30:15+00:00")).is_err());
+        assert!(Cursor::decode(&encode("yesterday|abc")).is_err());
+        assert!(Cursor::decode(&base64::encode_config(
+            [0xff, 0xfe, b'|', b'a'],
+            base64::URL_SAFE_NO_PAD
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn contains_pattern_escapes_wildcards() {
+        assert_eq!(contains_pattern("coffee"), "%coffee%");
+        assert_eq!(contains_pattern("100%"), "%100\\%%");
+        assert_eq!(contains_pattern("a_b"), "%a\\_b%");
+        assert_eq!(contains_pattern("c:\\d"), "%c:\\\\d%");
+    }
+
+    #[test]
+    fn time_range_includes_end_date() {
+        let filter = Filter {
+            from: Some(NaiveDate::from_ymd(2020, 8, 1)),
+            to: Some(NaiveDate::from_ymd(2020, 8, 31)),
+            ..Filter::default()
+        };
+
+        let (from, to) = filter.time_range().unwrap();
+        assert_eq!(from, Some(Utc.ymd(2020, 8, 1).and_hms(0, 0, 0)));
+        assert_eq!(to, Some(Utc.ymd(2020, 9, 1).and_hms(0, 0, 0)));
+    }
+
+    #[test]
+    fn time_range_rejects_last_date() {
+        let filter = Filter {
+            to: Some(chrono::naive::MAX_DATE),
+            ..Filter::default()
+        };
+
+        assert!(filter.time_range().is_err());
+    }
 }
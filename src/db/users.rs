@@ -0,0 +1,53 @@
+use chrono::Utc;
+use sqlx::postgres::PgRow;
+use sqlx::Row;
+
+use super::Db;
+
+pub struct User {
+    pub id: i32,
+    pub username: String,
+    pub password_hash: String,
+}
+
+/// Gets a user by their username.
+pub async fn get_by_username(db: &Db, username: &str) -> anyhow::Result<Option<User>> {
+    let sql = "
+        SELECT id, username, password_hash
+        FROM users
+        WHERE username = $1
+    ";
+
+    let user = sqlx::query(sql)
+        .bind(username)
+        .try_map(|row: PgRow| {
+            Ok(User {
+                id: row.get(0),
+                username: row.get(1),
+                password_hash: row.get(2),
+            })
+        })
+        .fetch_optional(db.pool())
+        .await?;
+
+    Ok(user)
+}
+
+/// Creates a new user, returning their id.
+pub async fn insert(db: &Db, username: &str, password_hash: &str) -> anyhow::Result<i32> {
+    let sql = "
+        INSERT INTO users (username, password_hash, created_at)
+        VALUES ($1, $2, $3)
+        RETURNING id
+    ";
+
+    let id = sqlx::query(sql)
+        .bind(username)
+        .bind(password_hash)
+        .bind(Utc::now())
+        .try_map(|row: PgRow| Ok(row.get(0)))
+        .fetch_one(db.pool())
+        .await?;
+
+    Ok(id)
+}
@@ -1,6 +1,7 @@
-use actix_web::{dev::Payload, FromRequest, HttpRequest};
+use actix_web::{dev::Payload, error::ErrorUnauthorized, FromRequest, HttpRequest};
 use futures::future::Ready;
 
+use crate::auth::Identity;
 use crate::Db;
 
 impl FromRequest for Db {
@@ -12,3 +13,19 @@ impl FromRequest for Db {
         futures::future::ok(req.app_data::<Db>().unwrap().clone())
     }
 }
+
+impl FromRequest for Identity {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        // Set by the `ApiAuth` middleware.
+        futures::future::ready(
+            req.extensions()
+                .get::<Identity>()
+                .copied()
+                .ok_or_else(|| ErrorUnauthorized("login required")),
+        )
+    }
+}
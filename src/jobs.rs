@@ -0,0 +1,63 @@
+use std::sync::Arc;
+
+use chrono::Duration;
+
+use crate::{balances, cron, payments, sync, utils, Config, Db};
+
+/// Registers and starts the scheduled background jobs, returning a registry
+/// that tracks their schedules and run times.
+pub fn start(
+    config: &Config,
+    db: Db,
+    true_layer: Arc<true_layer::Client>,
+    sync: sync::Trigger,
+) -> cron::Registry {
+    let registry = cron::Registry::default();
+
+    cron::new("sync_transactions", &config.sync_schedule)
+        .registry(&registry)
+        .persist(db.clone())
+        .catch_up()
+        .with_state((db.clone(), sync))
+        .spawn_with_task(|(db, sync)| async move {
+            if let Err(e) = sync.queue_all(&db).await {
+                log::error!("failed to queue sync: {:#}", e);
+            }
+        });
+
+    cron::new("balance_snapshots", &config.balance_schedule)
+        .registry(&registry)
+        .overlap(cron::Overlap::Queue)
+        .persist(db.clone())
+        .catch_up()
+        .with_state((db.clone(), true_layer.clone()))
+        .spawn_with_task(|(db, true_layer)| async move {
+            if let Err(e) = balances::snapshot_all(&db, &true_layer).await {
+                log::error!("failed to save balances: {:#}", e);
+            }
+        });
+
+    cron::new("payments", &config.payments_schedule)
+        .registry(&registry)
+        .persist(db.clone())
+        .catch_up()
+        .with_state((db.clone(), true_layer.clone()))
+        .spawn_with_task(|(db, true_layer)| async move {
+            if let Err(e) = payments::sync_all(&db, &true_layer).await {
+                log::error!("failed to sync payments: {:#}", e);
+            }
+        });
+
+    cron::new("token_refresh", &config.token_refresh_schedule)
+        .registry(&registry)
+        .persist(db.clone())
+        .with_state((db, true_layer))
+        .spawn_with_task(|(db, true_layer)| async move {
+            // Renew anything that would otherwise expire before the next run.
+            if let Err(e) = utils::refresh_tokens(&db, &true_layer, Duration::minutes(30)).await {
+                log::error!("failed to refresh tokens: {:#}", e);
+            }
+        });
+
+    registry
+}
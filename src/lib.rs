@@ -1,11 +1,18 @@
 mod config;
 mod ext;
 
+pub mod auth;
+pub mod auth_state;
+pub mod balances;
 pub mod cron;
+pub mod crypto;
 pub mod db;
+pub mod jobs;
 pub mod migrations;
+pub mod payments;
 pub mod services;
 pub mod sync;
+pub mod upcoming;
 pub mod utils;
 
 pub use config::Config;
@@ -1,13 +1,19 @@
+use std::env;
+use std::io;
 use std::path::Path;
 
 use actix_files::NamedFile;
+use actix_session::CookieSession;
 use actix_web::{
+    cookie::SameSite,
     middleware::Logger,
     web::{self, Data},
     App, HttpRequest, HttpServer, Responder,
 };
 
 use env_logger::Env;
+use fintrack::auth::RequireLogin;
+use fintrack::crypto::{derive_key, Cipher};
 use fintrack::utils::AuthProvider;
 use fintrack::{services, Config, Db};
 use true_layer::Client as TrueLayerClient;
@@ -17,23 +23,61 @@ async fn main() -> anyhow::Result<()> {
     dotenv::dotenv().ok();
     env_logger::from_env(Env::default().default_filter_or("info")).init();
 
-    let config = Config::from_env();
-    let db = Db::connect(&config.db_url).await?;
-    let true_layer = Data::new(TrueLayerClient::new(AuthProvider::new(db.clone())));
+    let config = Data::new(Config::from_env());
+    let db = Db::connect(&config.db_url, &config.secret_key).await?;
 
     fintrack::migrations::run(&db).await?;
-    fintrack::sync::start_worker(db.clone(), true_layer.clone().into_inner());
+
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if let Some((command, args)) = args.split_first() {
+        let res = run_command(&db, &config, command, args).await;
+        db.close().await;
+        return res;
+    }
+
+    let true_layer = Data::new(TrueLayerClient::new(AuthProvider::new(db.clone())));
+    let sync = Data::new(fintrack::sync::start_worker(
+        db.clone(),
+        true_layer.clone().into_inner(),
+        config.sync_lookback_days,
+    ));
+
+    let jobs = Data::new(fintrack::jobs::start(
+        &config,
+        db.clone(),
+        true_layer.clone().into_inner(),
+        sync.get_ref().clone(),
+    ));
+
+    let balance_refresher = Data::new(fintrack::balances::Refresher::default());
 
     let address = &config.http_address;
     let port = config.http_port;
 
+    let session_key = derive_key(&config.secret_key, "fintrack session cookies");
+
     HttpServer::new({
+        let config = config.clone();
         let db = db.clone();
         move || {
             App::new()
+                .wrap(RequireLogin)
+                .wrap(
+                    CookieSession::signed(&session_key)
+                        .name("fintrack_session")
+                        .http_only(true)
+                        .secure(config.secure_cookies)
+                        // Not strict, since TrueLayer redirects back to the
+                        // connect callback from another site.
+                        .same_site(SameSite::Lax),
+                )
                 .wrap(Logger::default())
+                .app_data(config.clone())
                 .app_data(db.clone())
                 .app_data(true_layer.clone())
+                .app_data(sync.clone())
+                .app_data(jobs.clone())
+                .app_data(balance_refresher.clone())
                 .service(services::connect("/connect"))
                 .service(services::api("/api"))
                 .default_service(web::get().to(spa_fallback))
@@ -48,6 +92,60 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Runs a maintenance command instead of starting the server.
+async fn run_command(
+    db: &Db,
+    config: &Config,
+    command: &str,
+    args: &[String],
+) -> anyhow::Result<()> {
+    match command {
+        // Re-encrypts saved tokens with the current secret key. The previous
+        // key is read from `FINTRACK_OLD_SECRET_KEY`; if that isn't set, any
+        // tokens that were saved unencrypted are encrypted. Tokens are also
+        // encrypted whenever they're next read.
+        "rotate-key" => {
+            let old_key = env::var("FINTRACK_OLD_SECRET_KEY")
+                .map(String::into_bytes)
+                .unwrap_or_else(|_| config.secret_key.clone());
+
+            let count = fintrack::db::providers::rotate_key(db, &Cipher::new(&old_key)).await?;
+
+            log::info!("re-encrypted tokens for {} providers", count);
+
+            Ok(())
+        }
+        // Creates a user that can log in, reading their password from stdin.
+        "create-user" => {
+            let username = args
+                .get(0)
+                .ok_or_else(|| anyhow::anyhow!("usage: create-user <username>"))?;
+
+            eprint!("Password: ");
+            let mut password = String::new();
+            io::stdin().read_line(&mut password)?;
+
+            let password = password.trim_end_matches(&['\r', '\n'][..]);
+            if password.is_empty() {
+                return Err(anyhow::anyhow!("password must not be empty"));
+            }
+
+            let hash = fintrack::auth::hash_password(password)?;
+            let id = fintrack::db::users::insert(db, username, &hash).await?;
+
+            log::info!("created user '{}' with id {}", username, id);
+
+            let claimed = fintrack::db::providers::claim_unowned(db, id).await?;
+            if claimed > 0 {
+                log::info!("gave {} existing providers to '{}'", claimed, username);
+            }
+
+            Ok(())
+        }
+        _ => Err(anyhow::anyhow!("unknown command '{}'", command)),
+    }
+}
+
 async fn spa_fallback(req: HttpRequest) -> actix_web::Result<impl Responder> {
     let path = Path::new("client/build").join(req.path().trim_start_matches('/'));
     if path.is_file() {
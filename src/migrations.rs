@@ -12,9 +12,17 @@ struct Migration;
 pub async fn run(db: &Db) -> anyhow::Result<()> {
     let mut db_version = get_current_version(&db).await?;
 
+    // The embedded files aren't listed in any particular order.
+    let mut migrations = vec![];
     for file in Migration::iter() {
         let file: std::borrow::Cow<'_, str> = file;
         let version: i32 = file.split('_').next().unwrap().parse()?;
+        migrations.push((version, file));
+    }
+
+    migrations.sort_by_key(|(version, _)| *version);
+
+    for (version, file) in migrations {
         if version <= db_version {
             continue;
         }
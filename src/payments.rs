@@ -0,0 +1,101 @@
+use crate::db::{
+    self, accounts::Kind, direct_debits::DirectDebit, standing_orders::StandingOrder, Db,
+};
+
+/// Refreshes the saved direct debits and standing orders for every account.
+///
+/// Cards don't have either, so they are skipped. Direct debits and standing
+/// orders are synced independently, so a failure with one doesn't stop the
+/// other from being saved.
+pub async fn sync_all(db: &Db, true_layer: &true_layer::Client) -> anyhow::Result<()> {
+    for account in db::accounts::all(db).await? {
+        if account.kind != Kind::Account {
+            continue;
+        }
+
+        match sync_direct_debits(db, true_layer, &account.id).await {
+            Ok(count) => log::info!("saved {} direct debits for account '{}'", count, account.id),
+            Err(e) => log::error!(
+                "failed to sync direct debits for account '{}': {:#}",
+                account.id,
+                e
+            ),
+        }
+
+        match sync_standing_orders(db, true_layer, &account.id).await {
+            Ok(count) => log::info!(
+                "saved {} standing orders for account '{}'",
+                count,
+                account.id
+            ),
+            Err(e) => log::error!(
+                "failed to sync standing orders for account '{}': {:#}",
+                account.id,
+                e
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+/// Replaces the saved direct debits for an account, returning how many
+/// there are.
+async fn sync_direct_debits(
+    db: &Db,
+    true_layer: &true_layer::Client,
+    account: &str,
+) -> anyhow::Result<usize> {
+    let direct_debits = true_layer
+        .direct_debits(account)
+        .await?
+        .into_iter()
+        .map(|dd| DirectDebit {
+            id: dd.direct_debit_id,
+            account_id: account.to_owned(),
+            timestamp: dd.timestamp,
+            name: dd.name,
+            status: dd.status,
+            previous_payment_timestamp: dd.previous_payment_timestamp,
+            previous_payment_amount: dd.previous_payment_amount,
+            currency: dd.currency,
+        })
+        .collect::<Vec<_>>();
+
+    db::direct_debits::replace(db, account, &direct_debits).await?;
+
+    Ok(direct_debits.len())
+}
+
+/// Replaces the saved standing orders for an account, returning how many
+/// there are.
+async fn sync_standing_orders(
+    db: &Db,
+    true_layer: &true_layer::Client,
+    account: &str,
+) -> anyhow::Result<usize> {
+    let standing_orders = true_layer
+        .standing_orders(account)
+        .await?
+        .into_iter()
+        .map(|so| StandingOrder {
+            account_id: account.to_owned(),
+            timestamp: so.timestamp,
+            frequency: so.frequency,
+            status: so.status,
+            currency: so.currency,
+            reference: so.reference,
+            payee: so.payee,
+            next_payment_date: so.next_payment_date,
+            next_payment_amount: so.next_payment_amount,
+            first_payment_date: so.first_payment_date,
+            first_payment_amount: so.first_payment_amount,
+            final_payment_date: so.final_payment_date,
+            final_payment_amount: so.final_payment_amount,
+        })
+        .collect::<Vec<_>>();
+
+    db::standing_orders::replace(db, account, &standing_orders).await?;
+
+    Ok(standing_orders.len())
+}
@@ -1,22 +1,73 @@
 use actix_web::{
     dev::HttpServiceFactory,
-    error::ErrorInternalServerError,
-    web::{self, Data, Path},
-    HttpResponse, Responder,
+    error::{
+        ErrorBadRequest, ErrorConflict, ErrorForbidden, ErrorInternalServerError, ErrorNotFound,
+        ErrorUnauthorized,
+    },
+    web::{self, Data, Json, Path, Query},
+    HttpRequest, HttpResponse, Responder,
 };
 
+use actix_session::Session;
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use crate::{db, Db};
+use crate::auth::Identity;
+use crate::db::accounts::Access;
+use crate::db::api_tokens::{ApiToken, Scope};
+use crate::db::balances::{Balance, Interval};
+use crate::db::transactions::{Cursor, Filter};
+use crate::{auth, balances, cron, db, sync, upcoming, Config, Db};
 
 pub fn service(path: &str) -> impl HttpServiceFactory {
     web::scope(path)
+        .wrap(auth::ApiAuth::new(vec![format!("{}/login", path)]))
+        .route("/login", web::post().to(login))
+        .route("/logout", web::post().to(logout))
+        .route("/tokens", web::get().to(get_tokens))
+        .route("/tokens", web::post().to(create_token))
+        .route("/tokens/{id}", web::delete().to(revoke_token))
         .route("/accounts", web::get().to(get_accounts))
+        .route("/transactions", web::get().to(get_all_transactions))
+        .route("/search", web::get().to(search_transactions))
         .route("/accounts/{id}/balance", web::get().to(get_account_balance))
+        .route(
+            "/accounts/{id}/balance/history",
+            web::get().to(get_account_balance_history),
+        )
         .route(
             "/accounts/{id}/transactions",
             web::get().to(get_transactions),
         )
+        .route(
+            "/accounts/{id}/direct_debits",
+            web::get().to(get_direct_debits),
+        )
+        .route(
+            "/accounts/{id}/standing_orders",
+            web::get().to(get_standing_orders),
+        )
+        .route("/accounts/{id}/shares", web::get().to(get_shares))
+        .route("/accounts/{id}/shares", web::post().to(share_account))
+        .route(
+            "/accounts/{id}/shares/{user_id}",
+            web::delete().to(unshare_account),
+        )
+        .route("/accounts/{id}/sync", web::post().to(sync_account))
+        .route("/sync", web::post().to(sync_all))
+        .route("/sync/status", web::get().to(get_sync_status))
+        .route("/sync/runs/{id}", web::get().to(get_sync_run))
+        .route("/jobs", web::get().to(get_jobs))
+        .route("/providers", web::get().to(get_providers))
+        .route("/providers/{id}", web::patch().to(rename_provider))
+        .route("/providers/{id}", web::delete().to(delete_provider))
+        .route(
+            "/providers/{id}/disconnect",
+            web::post().to(disconnect_provider),
+        )
+        .route("/providers/{id}/info", web::get().to(get_provider_info))
+        .route("/upcoming", web::get().to(get_upcoming))
         .default_service(web::route().to(|| {
             HttpResponse::NotFound().json(&json!({
                 "error": "not_found"
@@ -24,32 +75,794 @@ pub fn service(path: &str) -> impl HttpServiceFactory {
         }))
 }
 
-async fn get_accounts(db: Db) -> actix_web::Result<impl Responder> {
-    let accounts = db::accounts::all(&db)
+#[derive(Deserialize)]
+struct LoginRequest {
+    username: String,
+    password: String,
+}
+
+async fn login(
+    Json(body): Json<LoginRequest>,
+    session: Session,
+    db: Db,
+) -> actix_web::Result<impl Responder> {
+    let user = db::users::get_by_username(&db, &body.username)
+        .await
+        .map_err(|_| ErrorInternalServerError("failed to get user from db"))?;
+
+    let hash = user.as_ref().map(|user| user.password_hash.clone());
+    let valid = auth::check_login(hash, body.password).await?;
+
+    let user = match user {
+        Some(user) if valid => user,
+        _ => return Err(ErrorUnauthorized("invalid username or password")),
+    };
+
+    auth::login(&session, user.id)?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn logout(session: Session) -> impl Responder {
+    auth::logout(&session);
+    HttpResponse::NoContent().finish()
+}
+
+/// API tokens can only be managed from a login session, so that a leaked
+/// token can't be used to create more.
+fn require_session(identity: &Identity) -> actix_web::Result<()> {
+    if identity.via_token {
+        return Err(ErrorForbidden(
+            "api tokens can't be managed with an api token",
+        ));
+    }
+    Ok(())
+}
+
+async fn get_tokens(identity: Identity, db: Db) -> actix_web::Result<impl Responder> {
+    require_session(&identity)?;
+
+    let tokens = db::api_tokens::all(&db, identity.user_id)
+        .await
+        .map_err(|_| ErrorInternalServerError("failed to get api tokens from db"))?;
+
+    Ok(HttpResponse::Ok().json(tokens))
+}
+
+#[derive(Deserialize)]
+struct CreateToken {
+    name: String,
+    scope: Scope,
+}
+
+#[derive(Serialize)]
+struct CreatedToken {
+    #[serde(flatten)]
+    info: ApiToken,
+    /// Only returned when the token is created, since it isn't saved.
+    token: String,
+}
+
+async fn create_token(
+    identity: Identity,
+    Json(body): Json<CreateToken>,
+    db: Db,
+) -> actix_web::Result<impl Responder> {
+    require_session(&identity)?;
+
+    let (token, hash) = auth::generate_token();
+    let info = db::api_tokens::insert(&db, identity.user_id, &body.name, &hash, body.scope)
+        .await
+        .map_err(|_| ErrorInternalServerError("failed to save api token"))?;
+
+    Ok(HttpResponse::Created().json(CreatedToken { info, token }))
+}
+
+async fn revoke_token(
+    path: Path<(i32,)>,
+    identity: Identity,
+    db: Db,
+) -> actix_web::Result<impl Responder> {
+    require_session(&identity)?;
+
+    let (token_id,) = path.into_inner();
+    let revoked = db::api_tokens::revoke(&db, identity.user_id, token_id)
+        .await
+        .map_err(|_| ErrorInternalServerError("failed to revoke api token"))?;
+
+    if !revoked {
+        return Err(ErrorNotFound("api token not found"));
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Checks that the user can see an account, and has at least the given
+/// access to it.
+async fn check_access(
+    db: &Db,
+    identity: &Identity,
+    account: &str,
+    required: Access,
+) -> actix_web::Result<()> {
+    let access = db::accounts::access(db, identity.user_id, account)
+        .await
+        .map_err(|_| ErrorInternalServerError("failed to get account from db"))?
+        .ok_or_else(|| ErrorNotFound("account not found"))?;
+
+    if access < required {
+        return Err(ErrorForbidden("insufficient access to account"));
+    }
+
+    Ok(())
+}
+
+async fn get_accounts(identity: Identity, db: Db) -> actix_web::Result<impl Responder> {
+    let accounts = db::accounts::for_user(&db, identity.user_id)
         .await
         .map_err(|_| ErrorInternalServerError("failed to get accounts from db"))?;
 
     Ok(HttpResponse::Ok().json(accounts))
 }
 
+async fn get_shares(
+    path: Path<(String,)>,
+    identity: Identity,
+    db: Db,
+) -> actix_web::Result<impl Responder> {
+    let (account_id,) = path.into_inner();
+
+    check_access(&db, &identity, &account_id, Access::Owner).await?;
+
+    let shares = db::accounts::shares(&db, &account_id)
+        .await
+        .map_err(|_| ErrorInternalServerError("failed to get account shares from db"))?;
+
+    Ok(HttpResponse::Ok().json(shares))
+}
+
+#[derive(Deserialize)]
+struct ShareAccount {
+    username: String,
+    access: Access,
+}
+
+/// Shares an account with another user, or changes their access to it.
+async fn share_account(
+    path: Path<(String,)>,
+    Json(body): Json<ShareAccount>,
+    identity: Identity,
+    db: Db,
+) -> actix_web::Result<impl Responder> {
+    let (account_id,) = path.into_inner();
+
+    check_access(&db, &identity, &account_id, Access::Owner).await?;
+
+    if body.access == Access::Owner {
+        return Err(ErrorBadRequest(
+            "accounts can only be shared with read or write access",
+        ));
+    }
+
+    let user = db::users::get_by_username(&db, &body.username)
+        .await
+        .map_err(|_| ErrorInternalServerError("failed to get user from db"))?
+        .ok_or_else(|| ErrorNotFound("user not found"))?;
+
+    if user.id == identity.user_id {
+        return Err(ErrorBadRequest("accounts can't be shared with their owner"));
+    }
+
+    db::accounts::share(&db, &account_id, user.id, body.access)
+        .await
+        .map_err(|_| ErrorInternalServerError("failed to share account"))?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn unshare_account(
+    path: Path<(String, i32)>,
+    identity: Identity,
+    db: Db,
+) -> actix_web::Result<impl Responder> {
+    let (account_id, user_id) = path.into_inner();
+
+    check_access(&db, &identity, &account_id, Access::Owner).await?;
+
+    let removed = db::accounts::unshare(&db, &account_id, user_id)
+        .await
+        .map_err(|_| ErrorInternalServerError("failed to unshare account"))?;
+
+    if !removed {
+        return Err(ErrorNotFound("account is not shared with user"));
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Deserialize)]
+struct BalanceQuery {
+    #[serde(default)]
+    refresh: bool,
+}
+
+#[derive(Serialize)]
+struct BalanceResponse {
+    #[serde(flatten)]
+    balance: Balance,
+    stale: bool,
+}
+
+/// Gets the most recently saved balance for an account.
+///
+/// A balance older than the configured max age is still returned, but is
+/// flagged as stale and refreshed in the background, with at most one refresh
+/// running per account. Passing `refresh=true` fetches the live balance
+/// instead.
 async fn get_account_balance(
     path: Path<(String,)>,
+    Query(query): Query<BalanceQuery>,
+    identity: Identity,
+    config: Data<Config>,
+    db: Db,
     true_layer: Data<true_layer::Client>,
+    refresher: Data<balances::Refresher>,
 ) -> actix_web::Result<impl Responder> {
     let (account_id,) = path.into_inner();
-    let balance = true_layer
-        .account_balance(&account_id)
+
+    check_access(&db, &identity, &account_id, Access::Read).await?;
+
+    let account = db::accounts::get(&db, &account_id)
         .await
-        .map_err(|_| ErrorInternalServerError("failed to get account balance"))?;
+        .map_err(|_| ErrorInternalServerError("failed to get account from db"))?
+        .ok_or_else(|| ErrorNotFound("account not found"))?;
+
+    let saved = if query.refresh {
+        None
+    } else {
+        db::balances::latest(&db, &account.id)
+            .await
+            .map_err(|_| ErrorInternalServerError("failed to get account balance from db"))?
+    };
+
+    let balance = match saved {
+        Some(balance) => balance,
+        None => {
+            let balance = balances::fetch(&db, &true_layer, &account)
+                .await
+                .map_err(|_| ErrorInternalServerError("failed to get account balance"))?;
+
+            return Ok(HttpResponse::Ok().json(BalanceResponse {
+                balance,
+                stale: false,
+            }));
+        }
+    };
+
+    let stale = Utc::now() - balance.fetched_at > Duration::minutes(config.balance_max_age_mins);
+
+    if stale {
+        refresher.refresh(db, true_layer.into_inner(), account);
+    }
+
+    Ok(HttpResponse::Ok().json(BalanceResponse { balance, stale }))
+}
+
+#[derive(Deserialize)]
+struct BalanceHistoryQuery {
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+    interval: Option<Interval>,
+}
+
+impl BalanceHistoryQuery {
+    /// Gets the range of timestamps to show, where the end is exclusive.
+    /// Both dates are inclusive, and default to the year up to `today`.
+    fn time_range(&self, today: NaiveDate) -> actix_web::Result<(DateTime<Utc>, DateTime<Utc>)> {
+        let to = self.to.unwrap_or(today);
+        let from = match self.from {
+            Some(from) => from,
+            None => to
+                .checked_sub_signed(Duration::days(365))
+                .ok_or_else(|| ErrorBadRequest("'to' date is out of range"))?,
+        };
 
-    Ok(HttpResponse::Ok().json(balance))
+        let to = Utc
+            .from_utc_date(&to)
+            .succ_opt()
+            .ok_or_else(|| ErrorBadRequest("'to' date is out of range"))?
+            .and_hms(0, 0, 0);
+        let from = Utc.from_utc_date(&from).and_hms(0, 0, 0);
+
+        Ok((from, to))
+    }
 }
 
-async fn get_transactions(path: Path<(String,)>, db: Db) -> actix_web::Result<impl Responder> {
+async fn get_account_balance_history(
+    path: Path<(String,)>,
+    Query(query): Query<BalanceHistoryQuery>,
+    identity: Identity,
+    db: Db,
+) -> actix_web::Result<impl Responder> {
     let (account_id,) = path.into_inner();
-    let transactions = db::transactions::all(&db, &account_id)
+
+    check_access(&db, &identity, &account_id, Access::Read).await?;
+
+    let (from, to) = query.time_range(Utc::today().naive_utc())?;
+    let interval = query.interval.unwrap_or(Interval::Day);
+
+    let history = db::balances::history(&db, &account_id, from, to, interval)
         .await
-        .map_err(|_| ErrorInternalServerError("failed to get transactions from db"))?;
+        .map_err(|_| ErrorInternalServerError("failed to get balance history from db"))?;
+
+    Ok(HttpResponse::Ok().json(history))
+}
+
+#[derive(Deserialize)]
+struct PageQuery {
+    cursor: Option<String>,
+    limit: Option<i64>,
+}
+
+impl PageQuery {
+    const DEFAULT_LIMIT: i64 = 50;
+    const MAX_LIMIT: i64 = 500;
+
+    fn cursor(&self) -> actix_web::Result<Option<Cursor>> {
+        self.cursor
+            .as_deref()
+            .map(Cursor::decode)
+            .transpose()
+            .map_err(|_| ErrorBadRequest("invalid cursor"))
+    }
+
+    fn limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(Self::DEFAULT_LIMIT)
+            .max(1)
+            .min(Self::MAX_LIMIT)
+    }
+}
+
+/// Checks that a filter from a query string can be used.
+fn check_filter(filter: &Filter) -> actix_web::Result<()> {
+    filter
+        .time_range()
+        .map(|_| ())
+        .map_err(|e| ErrorBadRequest(e.to_string()))
+}
+
+async fn get_transactions(
+    path: Path<(String,)>,
+    Query(filter): Query<Filter>,
+    Query(page): Query<PageQuery>,
+    identity: Identity,
+    db: Db,
+) -> actix_web::Result<impl Responder> {
+    let (account_id,) = path.into_inner();
+
+    check_access(&db, &identity, &account_id, Access::Read).await?;
+    check_filter(&filter)?;
+
+    let cursor = page.cursor()?;
+    let transactions = db::transactions::page(
+        &db,
+        identity.user_id,
+        Some(&[account_id]),
+        &filter,
+        cursor.as_ref(),
+        page.limit(),
+    )
+    .await
+    .map_err(|_| ErrorInternalServerError("failed to get transactions from db"))?;
+
+    Ok(HttpResponse::Ok().json(transactions))
+}
+
+#[derive(Deserialize)]
+struct FeedQuery {
+    /// Comma separated account ids, defaulting to every account if missing
+    /// or empty.
+    accounts: Option<String>,
+}
+
+async fn get_all_transactions(
+    Query(query): Query<FeedQuery>,
+    Query(filter): Query<Filter>,
+    Query(page): Query<PageQuery>,
+    identity: Identity,
+    db: Db,
+) -> actix_web::Result<impl Responder> {
+    let accounts = query
+        .accounts
+        .map(|accounts| {
+            accounts
+                .split(',')
+                .filter(|id| !id.is_empty())
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        })
+        .filter(|accounts| !accounts.is_empty());
+
+    // Treat accounts the user can't see like any other account endpoint,
+    // rather than silently leaving them out.
+    for account in accounts.iter().flatten() {
+        check_access(&db, &identity, account, Access::Read).await?;
+    }
+
+    check_filter(&filter)?;
+
+    let cursor = page.cursor()?;
+    let transactions = db::transactions::page(
+        &db,
+        identity.user_id,
+        accounts.as_deref(),
+        &filter,
+        cursor.as_ref(),
+        page.limit(),
+    )
+    .await
+    .map_err(|_| ErrorInternalServerError("failed to get transactions from db"))?;
 
     Ok(HttpResponse::Ok().json(transactions))
 }
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+    limit: Option<i64>,
+}
+
+async fn search_transactions(
+    Query(query): Query<SearchQuery>,
+    Query(filter): Query<Filter>,
+    identity: Identity,
+    db: Db,
+) -> actix_web::Result<impl Responder> {
+    let text = query.q.trim();
+    if text.is_empty() {
+        return Err(ErrorBadRequest("'q' query parameter must not be empty"));
+    }
+
+    check_filter(&filter)?;
+
+    let limit = query.limit.unwrap_or(20).max(1).min(100);
+
+    let matches = db::transactions::search(&db, identity.user_id, text, &filter, limit)
+        .await
+        .map_err(|_| ErrorInternalServerError("failed to search transactions"))?;
+
+    Ok(HttpResponse::Ok().json(matches))
+}
+
+async fn get_sync_status(identity: Identity, db: Db) -> actix_web::Result<impl Responder> {
+    let runs = db::sync_runs::latest(&db, identity.user_id)
+        .await
+        .map_err(|_| ErrorInternalServerError("failed to get sync status from db"))?;
+
+    Ok(HttpResponse::Ok().json(runs))
+}
+
+async fn get_direct_debits(
+    path: Path<(String,)>,
+    identity: Identity,
+    db: Db,
+) -> actix_web::Result<impl Responder> {
+    let (account_id,) = path.into_inner();
+
+    check_access(&db, &identity, &account_id, Access::Read).await?;
+
+    let direct_debits = db::direct_debits::all(&db, &account_id)
+        .await
+        .map_err(|_| ErrorInternalServerError("failed to get direct debits from db"))?;
+
+    Ok(HttpResponse::Ok().json(direct_debits))
+}
+
+async fn get_standing_orders(
+    path: Path<(String,)>,
+    identity: Identity,
+    db: Db,
+) -> actix_web::Result<impl Responder> {
+    let (account_id,) = path.into_inner();
+
+    check_access(&db, &identity, &account_id, Access::Read).await?;
+
+    let standing_orders = db::standing_orders::all(&db, &account_id)
+        .await
+        .map_err(|_| ErrorInternalServerError("failed to get standing orders from db"))?;
+
+    Ok(HttpResponse::Ok().json(standing_orders))
+}
+
+async fn sync_account(
+    path: Path<(String,)>,
+    identity: Identity,
+    db: Db,
+    trigger: Data<sync::Trigger>,
+) -> actix_web::Result<impl Responder> {
+    let (account_id,) = path.into_inner();
+
+    check_access(&db, &identity, &account_id, Access::Write).await?;
+
+    let account = db::accounts::get(&db, &account_id)
+        .await
+        .map_err(|_| ErrorInternalServerError("failed to get account from db"))?
+        .ok_or_else(|| ErrorNotFound("account not found"))?;
+
+    if account.archived_at.is_some() {
+        return Err(ErrorConflict("account has been archived"));
+    }
+    if account.closed_at.is_some() {
+        return Err(ErrorConflict("account has been closed"));
+    }
+
+    let run_id = trigger
+        .queue(&db, &account_id)
+        .await
+        .map_err(|_| ErrorInternalServerError("failed to queue sync"))?;
+
+    Ok(HttpResponse::Accepted().json(json!({ "run_id": run_id })))
+}
+
+/// Queues a sync for every open account the user can write to.
+async fn sync_all(
+    identity: Identity,
+    db: Db,
+    trigger: Data<sync::Trigger>,
+) -> actix_web::Result<impl Responder> {
+    let accounts = db::accounts::for_user(&db, identity.user_id)
+        .await
+        .map_err(|_| ErrorInternalServerError("failed to get accounts from db"))?;
+
+    let mut runs = vec![];
+
+    let syncable = accounts.iter().filter(|a| {
+        a.access >= Access::Write
+            && a.account.archived_at.is_none()
+            && a.account.closed_at.is_none()
+    });
+
+    for account in syncable {
+        let account_id = &account.account.id;
+        let run_id = trigger
+            .queue(&db, account_id)
+            .await
+            .map_err(|_| ErrorInternalServerError("failed to queue sync"))?;
+
+        runs.push(json!({ "account_id": account_id, "run_id": run_id }));
+    }
+
+    Ok(HttpResponse::Accepted().json(runs))
+}
+
+async fn get_sync_run(
+    path: Path<(i32,)>,
+    identity: Identity,
+    db: Db,
+) -> actix_web::Result<impl Responder> {
+    let (run_id,) = path.into_inner();
+    let run = db::sync_runs::get(&db, identity.user_id, run_id)
+        .await
+        .map_err(|_| ErrorInternalServerError("failed to get sync run from db"))?
+        .ok_or_else(|| ErrorNotFound("sync run not found"))?;
+
+    Ok(HttpResponse::Ok().json(run))
+}
+
+async fn get_jobs(registry: Data<cron::Registry>) -> impl Responder {
+    HttpResponse::Ok().json(registry.jobs())
+}
+
+#[derive(Serialize)]
+struct ProviderResponse {
+    #[serde(flatten)]
+    provider: db::providers::Summary,
+    /// Where to send the user to re-authorise the provider, e.g. when its
+    /// consent is about to expire.
+    reauth_url: String,
+}
+
+async fn get_providers(
+    req: HttpRequest,
+    identity: Identity,
+    db: Db,
+) -> actix_web::Result<impl Responder> {
+    let connect = req.url_for_static("connect")?;
+
+    let providers = db::providers::all(&db, identity.user_id)
+        .await
+        .map_err(|_| ErrorInternalServerError("failed to get providers from db"))?
+        .into_iter()
+        .map(|provider| {
+            let mut reauth_url = connect.clone();
+            reauth_url
+                .query_pairs_mut()
+                .append_pair("provider", &provider.truelayer_id);
+
+            ProviderResponse {
+                provider,
+                reauth_url: reauth_url.to_string(),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    Ok(HttpResponse::Ok().json(providers))
+}
+
+#[derive(Deserialize)]
+struct RenameProvider {
+    display_name: String,
+}
+
+async fn rename_provider(
+    path: Path<(String,)>,
+    Json(body): Json<RenameProvider>,
+    identity: Identity,
+    db: Db,
+) -> actix_web::Result<impl Responder> {
+    let (provider_id,) = path.into_inner();
+
+    let renamed = db::providers::rename(&db, identity.user_id, &provider_id, &body.display_name)
+        .await
+        .map_err(|_| ErrorInternalServerError("failed to rename provider"))?;
+
+    if !renamed {
+        return Err(ErrorNotFound("provider not found"));
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Revokes access for a provider and archives its accounts, keeping their
+/// transactions and balances.
+async fn disconnect_provider(
+    path: Path<(String,)>,
+    identity: Identity,
+    db: Db,
+    true_layer: Data<true_layer::Client>,
+) -> actix_web::Result<impl Responder> {
+    let (provider_id,) = path.into_inner();
+
+    check_owner(&db, &identity, &provider_id).await?;
+    revoke_provider(&true_layer, &provider_id).await;
+
+    db::providers::disconnect(&db, &provider_id)
+        .await
+        .map_err(|_| ErrorInternalServerError("failed to disconnect provider"))?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Revokes access for a provider and deletes it, along with its accounts and
+/// everything saved for them.
+async fn delete_provider(
+    path: Path<(String,)>,
+    identity: Identity,
+    db: Db,
+    true_layer: Data<true_layer::Client>,
+) -> actix_web::Result<impl Responder> {
+    let (provider_id,) = path.into_inner();
+
+    check_owner(&db, &identity, &provider_id).await?;
+    revoke_provider(&true_layer, &provider_id).await;
+
+    db::providers::delete(&db, &provider_id)
+        .await
+        .map_err(|_| ErrorInternalServerError("failed to delete provider"))?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Checks that a provider exists and was connected by the user.
+async fn check_owner(db: &Db, identity: &Identity, provider_id: &str) -> actix_web::Result<()> {
+    let owned = db::providers::owned_by(db, provider_id, identity.user_id)
+        .await
+        .map_err(|_| ErrorInternalServerError("failed to get provider from db"))?;
+
+    if !owned {
+        return Err(ErrorNotFound("provider not found"));
+    }
+
+    Ok(())
+}
+
+async fn revoke_provider(true_layer: &true_layer::Client, provider_id: &str) {
+    // The consent may already have expired or been revoked from the bank's
+    // side, in which case there's nothing to revoke and the provider should
+    // still be removed.
+    if let Err(e) = true_layer.revoke(provider_id).await {
+        log::warn!(
+            "failed to revoke access for provider '{}': {:#}",
+            provider_id,
+            e
+        );
+    }
+}
+
+/// Gets the identity of the account holder from the provider. This isn't
+/// saved, so is always fetched live.
+async fn get_provider_info(
+    path: Path<(String,)>,
+    identity: Identity,
+    db: Db,
+    true_layer: Data<true_layer::Client>,
+) -> actix_web::Result<impl Responder> {
+    let (provider_id,) = path.into_inner();
+
+    check_owner(&db, &identity, &provider_id).await?;
+
+    let info = true_layer
+        .info(&provider_id)
+        .await
+        .map_err(|_| ErrorInternalServerError("failed to get provider info"))?;
+
+    Ok(HttpResponse::Ok().json(info))
+}
+
+#[derive(Deserialize)]
+struct UpcomingQuery {
+    days: Option<i64>,
+}
+
+/// How far ahead upcoming payments can be projected.
+const MAX_UPCOMING_DAYS: i64 = 366;
+
+async fn get_upcoming(
+    Query(query): Query<UpcomingQuery>,
+    identity: Identity,
+    db: Db,
+) -> actix_web::Result<impl Responder> {
+    let days = query.days.unwrap_or(30);
+    if !(1..=MAX_UPCOMING_DAYS).contains(&days) {
+        return Err(ErrorBadRequest(format!(
+            "'days' must be between 1 and {}",
+            MAX_UPCOMING_DAYS
+        )));
+    }
+
+    let from = Utc::today().naive_utc();
+    let to = from + Duration::days(days);
+
+    let payments = upcoming::between(&db, identity.user_id, from, to)
+        .await
+        .map_err(|_| ErrorInternalServerError("failed to get upcoming payments"))?;
+
+    Ok(HttpResponse::Ok().json(payments))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history_query(from: Option<NaiveDate>, to: Option<NaiveDate>) -> BalanceHistoryQuery {
+        BalanceHistoryQuery {
+            from,
+            to,
+            interval: None,
+        }
+    }
+
+    #[test]
+    fn balance_history_defaults_to_last_year() {
+        let today = NaiveDate::from_ymd(2020, 8, 1);
+        let (from, to) = history_query(None, None).time_range(today).unwrap();
+
+        assert_eq!(from, Utc.ymd(2019, 8, 2).and_hms(0, 0, 0));
+        assert_eq!(to, Utc.ymd(2020, 8, 2).and_hms(0, 0, 0));
+    }
+
+    #[test]
+    fn balance_history_rejects_out_of_range_dates() {
+        let today = NaiveDate::from_ymd(2020, 8, 1);
+
+        let query = history_query(None, Some(chrono::naive::MAX_DATE));
+        assert!(query.time_range(today).is_err());
+
+        let query = history_query(None, Some(chrono::naive::MIN_DATE));
+        assert!(query.time_range(today).is_err());
+
+        let query = history_query(Some(chrono::naive::MIN_DATE), Some(today));
+        assert!(query.time_range(today).is_ok());
+    }
+}
@@ -1,21 +1,32 @@
 use actix_web::{
     dev::HttpServiceFactory,
-    error::{ErrorBadRequest, ErrorInternalServerError},
+    error::{ErrorBadRequest, ErrorInternalServerError, ErrorUnauthorized},
     guard,
     http::header,
     web::{self, Data, Query},
     HttpRequest, HttpResponse, Responder,
 };
 
+use actix_session::Session;
 use chrono::{Duration, Utc};
 use serde::{Deserialize, Serialize};
+use true_layer::AuthLinkOptions;
 
-use crate::db::{self, providers::Provider, Db};
-use crate::utils;
+use crate::db::{
+    self,
+    providers::{Provider, Upserted},
+    Db,
+};
+use crate::{auth, auth_state, sync, utils, Config};
 
 pub fn service(path: &str) -> impl HttpServiceFactory {
     web::scope(path)
-        .route("", web::get().to(connect))
+        .service(
+            web::resource("")
+                .name("connect")
+                .guard(guard::Get())
+                .to(connect),
+        )
         .service(
             web::resource("/callback")
                 .name("connect_callback")
@@ -25,12 +36,38 @@ pub fn service(path: &str) -> impl HttpServiceFactory {
         .default_service(web::route().to(HttpResponse::NotFound))
 }
 
+#[derive(Deserialize)]
+struct ConnectQuery {
+    /// Set when re-authorising an existing provider.
+    provider: Option<String>,
+}
+
 async fn connect(
     req: HttpRequest,
+    Query(query): Query<ConnectQuery>,
+    config: Data<Config>,
+    db: Db,
     true_layer: Data<true_layer::Client>,
+    session: Session,
 ) -> actix_web::Result<impl Responder> {
+    let user_id =
+        auth::current_user(&session).ok_or_else(|| ErrorUnauthorized("login required"))?;
+
     let callback = req.url_for_static("connect_callback")?;
-    let location = true_layer.auth_link(callback.as_str());
+
+    let issued = auth_state::issue(&db, &config.secret_key, user_id)
+        .await
+        .map_err(|_| ErrorInternalServerError("failed to create auth state"))?;
+
+    let location = true_layer.auth_link(
+        callback.as_str(),
+        &AuthLinkOptions {
+            provider: query.provider.as_deref(),
+            state: Some(&issued.state),
+            code_verifier: Some(&issued.code_verifier),
+        },
+    );
+
     Ok(HttpResponse::TemporaryRedirect()
         .set_header(header::LOCATION, location)
         .finish())
@@ -40,15 +77,22 @@ async fn connect(
 struct CallbackQuery {
     code: Option<String>,
     scope: Option<String>,
+    state: Option<String>,
     error: Option<String>,
 }
 
 async fn callback(
     req: HttpRequest,
     Query(query): Query<CallbackQuery>,
+    config: Data<Config>,
     true_layer: Data<true_layer::Client>,
+    sync: Data<sync::Trigger>,
+    session: Session,
     db: Db,
 ) -> actix_web::Result<impl Responder> {
+    let user_id =
+        auth::current_user(&session).ok_or_else(|| ErrorUnauthorized("login required"))?;
+
     if let Some(error) = query.error {
         return Err(ErrorBadRequest(error));
     }
@@ -62,8 +106,25 @@ async fn callback(
         }
     };
 
+    // The state must have been issued by us to this user and not used before,
+    // otherwise this could be a forged or replayed request.
+    let state = query
+        .state
+        .ok_or_else(|| ErrorBadRequest("'state' query parameter must be provided"))?;
+
+    let code_verifier = auth_state::consume(&db, &config.secret_key, user_id, &state)
+        .await
+        .map_err(|e| {
+            log::warn!("rejected connect callback: {:#}", e);
+            ErrorBadRequest("invalid or expired state")
+        })?;
+
     let token_res = true_layer
-        .exchange_code(&code, req.url_for_static("connect_callback")?.as_str())
+        .exchange_code(
+            &code,
+            req.url_for_static("connect_callback")?.as_str(),
+            Some(&code_verifier),
+        )
         .await
         .map_err(|_| ErrorInternalServerError("failed to exchange code for auth token"))?;
 
@@ -73,31 +134,90 @@ async fn callback(
         .map_err(|_| ErrorInternalServerError("failed to get metadata for auth tokens"))?;
 
     let expires_at = Utc::now() + Duration::seconds(token_res.expires_in);
+    let (consent_granted_at, consent_expires_at) = utils::consent_period(&metadata);
 
     let provider = Provider {
-        id: metadata.provider.provider_id,
+        truelayer_id: metadata.provider.provider_id,
+        user_id,
         display_name: metadata.provider.display_name,
         logo_url: metadata.provider.logo_uri,
         refresh_token: token_res.refresh_token,
         access_token: token_res.access_token,
         expires_at,
+        consent_granted_at,
+        consent_expires_at,
     };
 
-    db::providers::insert(&db, &provider)
-        .await
-        .map_err(|_| ErrorInternalServerError("failed to save provider to db"))?;
+    let (id, upserted) = match db::providers::upsert(&db, &provider).await {
+        Ok(res) => res,
+        Err(e) => {
+            // Nothing else knows about the new tokens, so don't leave them
+            // active.
+            log::error!(
+                "failed to save provider '{}': {:#}",
+                provider.truelayer_id,
+                e
+            );
+            if let Err(e) = true_layer.revoke_token(&provider.access_token).await {
+                log::warn!("failed to revoke unsaved tokens: {:#}", e);
+            }
+            return Err(ErrorInternalServerError("failed to save provider to db"));
+        }
+    };
 
-    utils::fetch_provider_accounts(&db, true_layer.as_ref(), &provider.id)
+    let created = upserted == Upserted::Created;
+    if created {
+        log::info!("new provider '{}' connected", id);
+    } else {
+        log::info!("existing provider '{}' reconnected", id);
+    }
+
+    let changes = utils::fetch_provider_accounts(&db, true_layer.as_ref(), &id)
         .await
         .map_err(|_| ErrorInternalServerError("failed to get accounts for provider"))?;
 
+    // Connecting again brings back any accounts that were archived when the
+    // provider was disconnected, or closed while its consent had lapsed.
+    let restored = db::accounts::restore(&db, &changes.accounts)
+        .await
+        .map_err(|_| ErrorInternalServerError("failed to restore accounts"))?;
+
+    if restored > 0 {
+        log::info!("restored {} accounts of provider '{}'", restored, id);
+    }
+
+    // Sync straight away rather than waiting for the next scheduled run.
+    for account in &changes.accounts {
+        sync.queue(&db, account)
+            .await
+            .map_err(|_| ErrorInternalServerError("failed to queue sync"))?;
+    }
+
+    // Tell the client what happened, so that it can show a message.
+    let result = ConnectResult {
+        connect: if created { "connected" } else { "reconnected" },
+        provider: &id,
+        accounts_added: changes.added.len(),
+        accounts_closed: changes.closed.len(),
+    };
+
     let index = format!(
-        "{}://{}",
+        "{}://{}/?{}",
         req.connection_info().scheme(),
-        req.connection_info().host()
+        req.connection_info().host(),
+        serde_urlencoded::to_string(&result)
+            .map_err(|_| ErrorInternalServerError("failed to encode connect result"))?
     );
 
     Ok(HttpResponse::TemporaryRedirect()
         .set_header(header::LOCATION, index)
         .finish())
 }
+
+#[derive(Serialize)]
+struct ConnectResult<'a> {
+    connect: &'a str,
+    provider: &'a str,
+    accounts_added: usize,
+    accounts_closed: usize,
+}
@@ -1,110 +1,396 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
-use chrono::{Duration, Utc};
+use anyhow::anyhow;
+use chrono::{DateTime, Duration, Utc};
+use tokio::sync::mpsc;
 use true_layer::{Client as TrueLayerClient, Transaction};
 
-use crate::{db, Db};
+use crate::db::{
+    self,
+    accounts::{Account, Kind},
+    transactions::{Status, UpsertCounts},
+    Db,
+};
+use crate::utils;
 
-const FIVE_MINS: std::time::Duration = std::time::Duration::from_secs(300);
+/// How far apart a pending transaction and its settled counterpart can be.
+const PENDING_MATCH_WINDOW_DAYS: i64 = 7;
 
-pub fn start_worker(db: Db, true_layer: Arc<TrueLayerClient>) {
-    tokio::task::spawn(worker(db, true_layer));
+/// A handle for requesting syncs from the background worker.
+#[derive(Clone)]
+pub struct Trigger(mpsc::UnboundedSender<()>);
+
+impl Trigger {
+    /// Queues a sync for an account and wakes the worker, returning the id of
+    /// the queued run.
+    ///
+    /// Requests for an account that already has a queued run are merged into
+    /// that run.
+    pub async fn queue(&self, db: &Db, account: &str) -> anyhow::Result<i32> {
+        let run = db::sync_runs::queue(db, account).await?;
+        // The worker only stops when the runtime shuts down, so there's
+        // nothing useful to do if it has gone away.
+        let _ = self.0.send(());
+        Ok(run)
+    }
+
+    /// Queues a sync for every account, returning (account id, run id) pairs.
+    pub async fn queue_all(&self, db: &Db) -> anyhow::Result<Vec<(String, i32)>> {
+        let mut runs = vec![];
+        for account in db::accounts::all(db).await? {
+            let run = self.queue(db, &account.id).await?;
+            runs.push((account.id, run));
+        }
+        Ok(runs)
+    }
 }
 
-async fn worker(db: Db, true_layer: Arc<TrueLayerClient>) {
-    loop {
-        if let Err(e) = sync_transactions(&db, true_layer.as_ref()).await {
-            log::error!("sync failed: {}", e);
+/// Starts the background sync worker, which syncs accounts as runs are
+/// queued for them through the returned [`Trigger`].
+///
+/// Each incremental sync re-fetches transactions from `lookback_days` days
+/// before today, so anything the bank posts late is still reconciled.
+pub fn start_worker(db: Db, true_layer: Arc<TrueLayerClient>, lookback_days: i64) -> Trigger {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let lookback = Duration::days(lookback_days);
+    tokio::task::spawn(worker(db, true_layer, lookback, rx));
+    Trigger(tx)
+}
+
+async fn worker(
+    db: Db,
+    true_layer: Arc<TrueLayerClient>,
+    lookback: Duration,
+    mut trigger: mpsc::UnboundedReceiver<()>,
+) {
+    while let Some(()) = trigger.recv().await {
+        match db::sync_runs::queued_accounts(&db).await {
+            Ok(accounts) => sync_transactions(&db, true_layer.as_ref(), lookback, &accounts).await,
+            Err(e) => log::error!("failed to get queued syncs: {}", e),
         }
-        tokio::time::delay_for(FIVE_MINS).await;
     }
 }
 
-async fn sync_transactions(db: &Db, true_layer: &TrueLayerClient) -> anyhow::Result<()> {
-    let today = Utc::now().date().and_hms(0, 0, 0);
+async fn sync_transactions(
+    db: &Db,
+    true_layer: &TrueLayerClient,
+    lookback: Duration,
+    accounts: &[String],
+) {
+    let since = (Utc::now() - lookback).date().and_hms(0, 0, 0);
+
+    refresh_account_details(db, true_layer, accounts).await;
+
+    // Each account is synced independently, so that a failure for one
+    // (e.g. an expired consent) doesn't hold up the rest.
+    for account in accounts {
+        if let Err(e) = sync_and_record(db, true_layer, account, since).await {
+            log::error!("failed to record sync for account '{}': {}", account, e);
+        }
+    }
+}
+
+/// Refreshes the saved details (e.g. type, currency and account number) of
+/// the given accounts, along with any other accounts from the same providers.
+async fn refresh_account_details(db: &Db, true_layer: &TrueLayerClient, accounts: &[String]) {
+    let providers = match db::accounts::providers_of(db, accounts).await {
+        Ok(providers) => providers,
+        Err(e) => {
+            log::error!("failed to get providers for accounts: {}", e);
+            return;
+        }
+    };
+
+    // Out of date details aren't a reason to hold up the sync, so failures
+    // are only logged.
+    for provider in providers {
+        // Refreshing a disconnected provider would bring back the accounts
+        // that were archived when it was disconnected.
+        match db::providers::status(db, &provider).await {
+            Ok(db::providers::Status::Connected) => {}
+            Ok(_) => continue,
+            Err(e) => {
+                log::warn!("failed to get status of provider '{}': {:#}", provider, e);
+                continue;
+            }
+        }
+
+        if let Err(e) = utils::fetch_provider_accounts(db, true_layer, &provider).await {
+            log::warn!(
+                "failed to refresh accounts for provider '{}': {:#}",
+                provider,
+                e
+            );
+        }
+    }
+}
+
+/// Syncs an account, recording the outcome as a sync run.
+async fn sync_and_record(
+    db: &Db,
+    true_layer: &TrueLayerClient,
+    account: &str,
+    since: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    let run = db::sync_runs::start(db, account).await?;
+
+    let res = async {
+        let account = db::accounts::get(db, account)
+            .await?
+            .ok_or_else(|| anyhow!("account not found"))?;
+
+        if account.archived_at.is_some() {
+            return Err(anyhow!("account has been archived"));
+        }
+
+        if account.closed_at.is_some() {
+            return Err(anyhow!("account has been closed"));
+        }
+
+        sync_account(db, true_layer, &account, since).await
+    };
+
+    match res.await {
+        Ok(counts) => db::sync_runs::succeed(db, run, &counts).await?,
+        Err(e) => {
+            log::error!("sync failed for account '{}': {:#}", account, e);
+            db::sync_runs::fail(db, run, &format!("{:#}", e)).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn sync_account(
+    db: &Db,
+    true_layer: &TrueLayerClient,
+    account: &Account,
+    since: DateTime<Utc>,
+) -> anyhow::Result<UpsertCounts> {
+    let kind = account.kind;
+    let account = account.id.as_str();
+    let mut counts = UpsertCounts::default();
+
+    let settled = if db::transactions::has_any(db, account).await? {
+        log::info!(
+            "syncing transactions since {} for account '{}'",
+            since,
+            account
+        );
 
-    for account in db::accounts::all(&db).await? {
-        if db::transactions::has_any(db, &account.id).await? {
+        let saved = db::transactions::after(&db, account, since).await?;
+        let new = fetch_transactions(true_layer, account, kind, since, Utc::now())
+            .await?
+            .into_iter()
+            .map(|t| true_layer_to_db(t, account, Status::Settled))
+            .collect::<Vec<_>>();
+
+        let changes = field_changes(&saved, &new);
+
+        if changed(&new, &saved) || !changes.is_empty() {
             log::info!(
-                "syncing transactions since {} for account '{}'",
-                today,
-                account.id
+                "changes detected for account '{}', refreshing transactions",
+                account
             );
 
-            let saved = db::transactions::ids_after(&db, &account.id, today).await?;
-            let new = true_layer
-                .transactions(&account.id, today, Utc::now())
-                .await?;
-
-            if changed(&new, saved) {
-                log::info!(
-                    "changes detected for account '{}', refreshing transactions",
-                    account.id
-                );
-
-                let new = new
-                    .into_iter()
-                    .map(|t| true_layer_to_db(t, &account.id))
-                    .collect::<Vec<_>>();
-
-                db::transactions::delete_after(&db, &account.id, today).await?;
-                db::transactions::insert_many(&db, &new).await?;
-
-                log::info!("{} transactions inserted into db", new.len());
-            } else {
-                log::info!(
-                    "no changes detected for account '{}', nothing to do",
-                    account.id,
-                );
-            }
+            let upserted =
+                db::transactions::upsert_after(&db, account, since, &new, &changes).await?;
+
+            log::info!(
+                "{} transactions inserted, {} updated and {} removed",
+                upserted.inserted,
+                upserted.updated,
+                upserted.removed
+            );
+
+            counts += upserted;
         } else {
             log::info!(
-                "first sync for account '{}', fetching all transactions",
-                account.id
+                "no changes detected for account '{}', nothing to do",
+                account,
+            );
+        }
+
+        new
+    } else {
+        log::info!(
+            "first sync for account '{}', fetching all transactions",
+            account
+        );
+
+        let to = Utc::now();
+        let from = to - Duration::days(365 * 6);
+
+        let transactions = fetch_transactions(true_layer, account, kind, from, to)
+            .await?
+            .into_iter()
+            .map(|t| true_layer_to_db(t, account, Status::Settled))
+            .collect::<Vec<_>>();
+
+        let upserted =
+            db::transactions::upsert_after(db, account, from, &transactions, &[]).await?;
+
+        log::info!("{} transactions inserted into db", upserted.inserted);
+
+        counts += upserted;
+
+        transactions
+    };
+
+    counts += sync_pending(db, true_layer, account, kind, &settled).await?;
+
+    Ok(counts)
+}
+
+async fn fetch_transactions(
+    true_layer: &TrueLayerClient,
+    account: &str,
+    kind: Kind,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> anyhow::Result<Vec<Transaction>> {
+    match kind {
+        Kind::Account => true_layer.transactions(account, from, to).await,
+        Kind::Card => true_layer.card_transactions(account, from, to).await,
+    }
+}
+
+/// Matches any saved pending transactions against the newly fetched
+/// `settled` ones, then saves the account's current pending transactions.
+async fn sync_pending(
+    db: &Db,
+    true_layer: &TrueLayerClient,
+    account: &str,
+    kind: Kind,
+    settled: &[db::transactions::Transaction],
+) -> anyhow::Result<UpsertCounts> {
+    let pending = db::transactions::unmatched_pending(db, account).await?;
+    let matched = db::transactions::matched_settled_ids(db, account).await?;
+    let matches = match_pending(&pending, settled, &matched);
+
+    if !matches.is_empty() {
+        db::transactions::mark_settled(db, &matches).await?;
+        log::info!(
+            "{} pending transactions settled for account '{}'",
+            matches.len(),
+            account
+        );
+    }
+
+    // Not every provider supports pending transactions, so a failure here
+    // shouldn't stop the settled transactions from being synced.
+    let pending = match kind {
+        Kind::Account => true_layer.pending_transactions(account).await,
+        Kind::Card => true_layer.card_pending_transactions(account).await,
+    };
+
+    let pending = match pending {
+        Ok(pending) => pending,
+        Err(e) => {
+            log::warn!(
+                "failed to get pending transactions for account '{}': {}",
+                account,
+                e
             );
+            return Ok(UpsertCounts::default());
+        }
+    };
+
+    let pending = pending
+        .into_iter()
+        .map(|t| true_layer_to_db(t, account, Status::Pending))
+        .collect::<Vec<_>>();
 
-            let to = Utc::now();
-            let from = to - Duration::days(365 * 6);
+    let counts = db::transactions::upsert_pending(db, account, &pending).await?;
 
-            let transactions = true_layer
-                .transactions(&account.id, from, to)
-                .await?
-                .into_iter()
-                .map(|t| db::transactions::Transaction {
-                    id: t.transaction_id,
-                    account_id: account.id.to_owned(),
-                    timestamp: t.timestamp,
-                    amount: t.amount,
-                    currency: t.currency,
-                    transaction_type: Some(t.transaction_type),
-                    category: Some(t.transaction_category),
-                    description: Some(t.description),
-                    merchant_name: t.merchant_name,
-                })
-                .collect::<Vec<_>>();
+    log::info!(
+        "{} pending transactions inserted, {} updated and {} removed",
+        counts.inserted,
+        counts.updated,
+        counts.removed
+    );
 
-            db::transactions::insert_many(db, &transactions).await?;
+    Ok(counts)
+}
 
-            log::info!("{} transactions inserted into db", transactions.len());
+/// Pairs up pending transactions with the settled transactions that replaced
+/// them, returning (pending id, settled id) pairs.
+///
+/// Settled transactions usually turn up under a different id, so they are
+/// matched on amount and merchant within a window around the pending
+/// transaction's timestamp. The closest pairs in time are matched first, and
+/// ties (e.g. two identical coffees) are broken by timestamp and then id, so
+/// the result doesn't depend on the order the transactions were loaded in.
+/// Settled transactions in `matched` have already been claimed and are
+/// skipped.
+fn match_pending(
+    pending: &[db::transactions::Transaction],
+    settled: &[db::transactions::Transaction],
+    matched: &[String],
+) -> Vec<(String, String)> {
+    let window = Duration::days(PENDING_MATCH_WINDOW_DAYS).num_seconds();
+    let matched = matched.iter().map(String::as_str).collect::<HashSet<_>>();
+
+    let mut candidates = vec![];
+    for p in pending {
+        for s in settled {
+            if matched.contains(s.id.as_str()) || s.amount != p.amount || !same_merchant(p, s) {
+                continue;
+            }
+
+            let distance = (s.timestamp - p.timestamp).num_seconds().abs();
+            if distance <= window {
+                candidates.push((distance, p, s));
+            }
         }
     }
 
-    Ok(())
+    candidates.sort_by(|(a_distance, a_p, a_s), (b_distance, b_p, b_s)| {
+        a_distance
+            .cmp(b_distance)
+            .then(a_p.timestamp.cmp(&b_p.timestamp))
+            .then(a_p.id.cmp(&b_p.id))
+            .then(a_s.timestamp.cmp(&b_s.timestamp))
+            .then(a_s.id.cmp(&b_s.id))
+    });
+
+    let mut pending_claimed = HashSet::new();
+    let mut settled_claimed = HashSet::new();
+    let mut matches = vec![];
+
+    for (_, p, s) in candidates {
+        if pending_claimed.contains(p.id.as_str()) || settled_claimed.contains(s.id.as_str()) {
+            continue;
+        }
+
+        pending_claimed.insert(p.id.as_str());
+        settled_claimed.insert(s.id.as_str());
+        matches.push((p.id.clone(), s.id.clone()));
+    }
+
+    matches
 }
 
-fn changed(new: &[Transaction], old: Vec<String>) -> bool {
+fn same_merchant(a: &db::transactions::Transaction, b: &db::transactions::Transaction) -> bool {
+    match (&a.merchant_name, &b.merchant_name) {
+        (Some(a), Some(b)) => a == b,
+        _ => a.description == b.description,
+    }
+}
+
+fn changed(new: &[db::transactions::Transaction], old: &[db::transactions::Transaction]) -> bool {
     if new.len() != old.len() {
         return true;
     }
 
     let mut ids = HashSet::new();
-    for id in old {
-        ids.insert(id);
+    for t in old {
+        ids.insert(&t.id);
     }
 
     for t in new {
-        if !ids.contains(&t.transaction_id) {
+        if !ids.contains(&t.id) {
             return true;
         }
     }
@@ -112,7 +398,97 @@ fn changed(new: &[Transaction], old: Vec<String>) -> bool {
     false
 }
 
-fn true_layer_to_db(t: Transaction, account: &str) -> db::transactions::Transaction {
+/// Compares the fetched transactions against the saved ones with the same
+/// ids, returning a change for every field that differs.
+fn field_changes(
+    old: &[db::transactions::Transaction],
+    new: &[db::transactions::Transaction],
+) -> Vec<db::transactions::Change> {
+    let old = old
+        .iter()
+        .map(|t| (t.id.as_str(), t))
+        .collect::<HashMap<_, _>>();
+
+    let mut changes = vec![];
+
+    for new in new {
+        let old = match old.get(new.id.as_str()) {
+            Some(old) => old,
+            None => continue,
+        };
+
+        let mut record = |field: &str, old_value: Option<String>, new_value: Option<String>| {
+            changes.push(db::transactions::Change {
+                transaction_id: new.id.clone(),
+                field: field.to_owned(),
+                old_value,
+                new_value,
+            })
+        };
+
+        if old.timestamp != new.timestamp {
+            record(
+                "timestamp",
+                Some(old.timestamp.to_rfc3339()),
+                Some(new.timestamp.to_rfc3339()),
+            );
+        }
+
+        // Compared as decimals rather than strings, since the saved amount
+        // may have a different scale to the fetched one.
+        if old.amount != new.amount {
+            record(
+                "amount",
+                Some(old.amount.to_string()),
+                Some(new.amount.to_string()),
+            );
+        }
+
+        if old.currency != new.currency {
+            record(
+                "currency",
+                Some(old.currency.clone()),
+                Some(new.currency.clone()),
+            );
+        }
+
+        if old.transaction_type != new.transaction_type {
+            record(
+                "type",
+                old.transaction_type.clone(),
+                new.transaction_type.clone(),
+            );
+        }
+
+        if old.category != new.category {
+            record("category", old.category.clone(), new.category.clone());
+        }
+
+        if old.description != new.description {
+            record(
+                "description",
+                old.description.clone(),
+                new.description.clone(),
+            );
+        }
+
+        if old.merchant_name != new.merchant_name {
+            record(
+                "merchant_name",
+                old.merchant_name.clone(),
+                new.merchant_name.clone(),
+            );
+        }
+    }
+
+    changes
+}
+
+fn true_layer_to_db(
+    t: Transaction,
+    account: &str,
+    status: Status,
+) -> db::transactions::Transaction {
     db::transactions::Transaction {
         id: t.transaction_id,
         account_id: account.to_owned(),
@@ -123,5 +499,117 @@ fn true_layer_to_db(t: Transaction, account: &str) -> db::transactions::Transact
         category: Some(t.transaction_category),
         description: Some(t.description),
         merchant_name: t.merchant_name,
+        status,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use rust_decimal::Decimal;
+
+    use super::*;
+
+    fn transaction(id: &str, minute: u32, status: Status) -> db::transactions::Transaction {
+        db::transactions::Transaction {
+            id: id.to_owned(),
+            account_id: "account".to_owned(),
+            timestamp: Utc.ymd(2020, 9, 1).and_hms(8, minute, 0),
+            amount: Decimal::new(-250, 2),
+            currency: "GBP".to_owned(),
+            transaction_type: Some("DEBIT".to_owned()),
+            category: Some("PURCHASE".to_owned()),
+            description: Some("COFFEE SHOP".to_owned()),
+            merchant_name: Some("Coffee Shop".to_owned()),
+            status,
+        }
+    }
+
+    fn pending(id: &str, minute: u32) -> db::transactions::Transaction {
+        transaction(id, minute, Status::Pending)
+    }
+
+    fn settled(id: &str, minute: u32) -> db::transactions::Transaction {
+        transaction(id, minute, Status::Settled)
+    }
+
+    fn sorted(mut matches: Vec<(String, String)>) -> Vec<(String, String)> {
+        matches.sort();
+        matches
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(p, s)| (p.to_string(), s.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn matches_closest_in_time() {
+        let pending = vec![pending("p1", 0), pending("p2", 30)];
+        let settled = vec![settled("s1", 31), settled("s2", 1)];
+
+        let matches = match_pending(&pending, &settled, &[]);
+
+        assert_eq!(sorted(matches), pairs(&[("p1", "s2"), ("p2", "s1")]));
+    }
+
+    #[test]
+    fn identical_transactions_match_regardless_of_order() {
+        let mut pending = vec![pending("p1", 0), pending("p2", 0)];
+        let mut settled = vec![settled("s1", 5), settled("s2", 5)];
+
+        let expected = pairs(&[("p1", "s1"), ("p2", "s2")]);
+        assert_eq!(sorted(match_pending(&pending, &settled, &[])), expected);
+
+        pending.reverse();
+        settled.reverse();
+        assert_eq!(sorted(match_pending(&pending, &settled, &[])), expected);
+    }
+
+    #[test]
+    fn closest_pair_wins_over_load_order() {
+        // Matching p1 first would take s1, leaving p2 without a match even
+        // though s1 is much closer to p2.
+        let pending = vec![pending("p1", 0), pending("p2", 20)];
+        let settled = vec![settled("s1", 20)];
+
+        let matches = match_pending(&pending, &settled, &[]);
+
+        assert_eq!(matches, pairs(&[("p2", "s1")]));
+    }
+
+    #[test]
+    fn skips_already_matched() {
+        let pending = vec![pending("p1", 0)];
+        let settled = vec![settled("s1", 1), settled("s2", 2)];
+
+        let matches = match_pending(&pending, &settled, &["s1".to_owned()]);
+
+        assert_eq!(matches, pairs(&[("p1", "s2")]));
+    }
+
+    #[test]
+    fn requires_same_amount_and_merchant() {
+        let pending = vec![pending("p1", 0)];
+
+        let mut other_amount = settled("s1", 1);
+        other_amount.amount = Decimal::new(-300, 2);
+        let mut other_merchant = settled("s2", 1);
+        other_merchant.merchant_name = Some("Bakery".to_owned());
+
+        let matches = match_pending(&pending, &[other_amount, other_merchant], &[]);
+
+        assert!(matches.is_empty());
+    }
+
+    #[test]
+    fn ignores_settled_outside_window() {
+        let pending = vec![pending("p1", 0)];
+        let mut late = settled("s1", 0);
+        late.timestamp = late.timestamp + Duration::days(PENDING_MATCH_WINDOW_DAYS + 1);
+
+        assert!(match_pending(&pending, &[late], &[]).is_empty());
     }
 }
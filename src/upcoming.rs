@@ -0,0 +1,490 @@
+use std::collections::HashMap;
+
+use chrono::{Datelike, Duration, NaiveDate, Utc, Weekday};
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::db::{
+    self, direct_debits::DirectDebit, standing_orders::StandingOrder, transactions::Transaction, Db,
+};
+
+/// How much transaction history is used to spot recurring payments.
+const RECURRING_HISTORY_DAYS: i64 = 400;
+
+/// The minimum number of past payments before something counts as recurring.
+const RECURRING_MIN_PAYMENTS: usize = 3;
+
+/// A payment that is expected to leave an account.
+#[derive(Debug, Serialize)]
+pub struct Payment {
+    pub account_id: String,
+    pub source: Source,
+    pub name: String,
+    pub date: NaiveDate,
+    /// Negative, like the amounts of outgoing transactions. Not known for
+    /// direct debits that haven't been paid before.
+    pub amount: Option<Decimal>,
+    pub currency: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    StandingOrder,
+    DirectDebit,
+    Recurring,
+}
+
+/// Projects every scheduled or recurring outgoing payment across the user's
+/// accounts that is expected between `from` and `to` (inclusive), ordered by
+/// date.
+pub async fn between(
+    db: &Db,
+    user: i32,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> anyhow::Result<Vec<Payment>> {
+    let mut payments = vec![];
+    let mut scheduled = vec![];
+
+    for account in db::accounts::for_user(db, user).await? {
+        let account = account.account;
+
+        // An empty name would be contained in every other name, and so leave
+        // out every recurring payment.
+        for so in db::standing_orders::all(db, &account.id).await? {
+            if let Some(payee) = so.payee.as_deref().filter(|p| !p.trim().is_empty()) {
+                scheduled.push(payee.to_lowercase());
+            }
+            payments.extend(standing_order_payments(&so, from, to));
+        }
+
+        for dd in db::direct_debits::all(db, &account.id).await? {
+            if !dd.name.trim().is_empty() {
+                scheduled.push(dd.name.to_lowercase());
+            }
+            payments.extend(direct_debit_payments(&dd, from, to));
+        }
+    }
+
+    let since = Utc::now() - Duration::days(RECURRING_HISTORY_DAYS);
+    let history = db::transactions::outgoing_after(db, user, since).await?;
+
+    // Anything paid by a standing order or direct debit will also show up in
+    // the transaction history, so leave those out to avoid double counting.
+    payments.extend(
+        recurring_payments(&history, from, to)
+            .into_iter()
+            .filter(|p| {
+                let name = p.name.to_lowercase();
+                !scheduled
+                    .iter()
+                    .any(|s| name.contains(s.as_str()) || s.contains(name.as_str()))
+            }),
+    );
+
+    payments.sort_by_key(|p| p.date);
+
+    Ok(payments)
+}
+
+fn standing_order_payments(so: &StandingOrder, from: NaiveDate, to: NaiveDate) -> Vec<Payment> {
+    let mut payments = vec![];
+
+    if so
+        .status
+        .as_deref()
+        .map_or(false, |s| !s.eq_ignore_ascii_case("active"))
+    {
+        return payments;
+    }
+
+    let mut date = match so.next_payment_date {
+        Some(date) => date.naive_utc().date(),
+        None => return payments,
+    };
+
+    let last = so.final_payment_date.map(|d| d.naive_utc().date());
+    let name = so
+        .payee
+        .clone()
+        .or_else(|| so.reference.clone())
+        .unwrap_or_else(|| "Standing order".to_owned());
+
+    while date <= to && last.map_or(true, |last| date <= last) {
+        if date >= from {
+            payments.push(Payment {
+                account_id: so.account_id.clone(),
+                source: Source::StandingOrder,
+                name: name.clone(),
+                date,
+                amount: so.next_payment_amount.map(|a| -a),
+                currency: so.currency.clone(),
+            });
+        }
+
+        date = match next_standing_order_date(&so.frequency, date) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+
+    payments
+}
+
+/// Works out the payment date after `date` from a standing order frequency,
+/// which uses the ISO 20022 codes (e.g. `IntrvlMnthDay:01:15`).
+fn next_standing_order_date(frequency: &str, date: NaiveDate) -> Option<NaiveDate> {
+    let mut parts = frequency.split(':');
+    let code = parts.next()?;
+    let interval = parts
+        .next()
+        .and_then(|i| i.parse::<u32>().ok())
+        .unwrap_or(1);
+
+    match code {
+        "EvryDay" => Some(date + Duration::days(1)),
+        "EvryWorkgDay" => {
+            let mut next = date + Duration::days(1);
+            while next.weekday() == Weekday::Sat || next.weekday() == Weekday::Sun {
+                next += Duration::days(1);
+            }
+            Some(next)
+        }
+        "IntrvlDay" => Some(date + Duration::days(interval.into())),
+        "IntrvlWkDay" => Some(date + Duration::weeks(interval.into())),
+        "IntrvlMnthDay" => {
+            // The day of the month is given too, so that payments don't
+            // drift earlier after a short month.
+            let next = add_months(date, interval);
+            match parts.next().and_then(|d| d.parse::<i32>().ok()) {
+                Some(day) => Some(with_day(next, day)),
+                None => Some(next),
+            }
+        }
+        "WkInMnthDay" => Some(add_months(date, 1)),
+        "QtrDay" => Some(add_months(date, 3)),
+        _ => None,
+    }
+}
+
+/// Assumes direct debits are taken monthly, on the same day of the month as
+/// the previous payment.
+fn direct_debit_payments(dd: &DirectDebit, from: NaiveDate, to: NaiveDate) -> Vec<Payment> {
+    let mut payments = vec![];
+
+    if !dd.status.eq_ignore_ascii_case("active") {
+        return payments;
+    }
+
+    let previous = match dd.previous_payment_timestamp {
+        Some(previous) => previous.naive_utc().date(),
+        None => return payments,
+    };
+
+    let mut months = 1;
+    let mut date = add_months(previous, months);
+
+    while date <= to {
+        if date >= from {
+            payments.push(Payment {
+                account_id: dd.account_id.clone(),
+                source: Source::DirectDebit,
+                name: dd.name.clone(),
+                date,
+                amount: dd.previous_payment_amount.map(|a| -a),
+                currency: dd.currency.clone(),
+            });
+        }
+
+        months += 1;
+        date = add_months(previous, months);
+    }
+
+    payments
+}
+
+/// Spots payments to the same merchant that happen at a regular interval, and
+/// projects them forward using the median gap between past payments.
+///
+/// A payment that's a little overdue as of `from` is still expected, so is
+/// projected for `from`. Payments that are more than one interval overdue are
+/// assumed to have stopped (e.g. a cancelled subscription), and aren't
+/// projected.
+fn recurring_payments(history: &[Transaction], from: NaiveDate, to: NaiveDate) -> Vec<Payment> {
+    let mut groups: HashMap<(&str, &str), Vec<&Transaction>> = HashMap::new();
+
+    for t in history {
+        let name = match t.merchant_name.as_deref().or(t.description.as_deref()) {
+            Some(name) => name,
+            None => continue,
+        };
+
+        groups
+            .entry((t.account_id.as_str(), name))
+            .or_default()
+            .push(t);
+    }
+
+    let mut payments = vec![];
+
+    for ((account_id, name), transactions) in groups {
+        if transactions.len() < RECURRING_MIN_PAYMENTS {
+            continue;
+        }
+
+        let dates = transactions
+            .iter()
+            .map(|t| t.timestamp.naive_utc().date())
+            .collect::<Vec<_>>();
+
+        let mut gaps = dates
+            .windows(2)
+            .map(|w| (w[1] - w[0]).num_days())
+            .collect::<Vec<_>>();
+
+        gaps.sort_unstable();
+        let median = gaps[gaps.len() / 2];
+
+        // Only weekly to quarterly payments, and only if every gap is close
+        // to the median (allowing for weekends and bank holidays).
+        if !(6..=100).contains(&median) {
+            continue;
+        }
+
+        let tolerance = (median / 10).max(3);
+        if gaps.iter().any(|gap| (gap - median).abs() > tolerance) {
+            continue;
+        }
+
+        let last = transactions[transactions.len() - 1];
+        let last_date = dates[dates.len() - 1];
+        if (from - last_date).num_days() > median + tolerance {
+            continue;
+        }
+
+        let mut date = last_date + Duration::days(median);
+
+        while date <= to {
+            // Only the first payment can be overdue, and only by up to the
+            // tolerance, since lapsed payments were skipped above.
+            payments.push(Payment {
+                account_id: account_id.to_owned(),
+                source: Source::Recurring,
+                name: name.to_owned(),
+                date: date.max(from),
+                amount: Some(last.amount),
+                currency: last.currency.clone(),
+            });
+
+            date += Duration::days(median);
+        }
+    }
+
+    payments
+}
+
+/// Adds a number of months to a date, clamping the day to the end of the
+/// month where necessary (e.g. 31st January + 1 month = 28th/29th February).
+fn add_months(date: NaiveDate, months: u32) -> NaiveDate {
+    let months = date.month0() + months;
+    let year = date.year() + (months / 12) as i32;
+    let month = months % 12 + 1;
+    let day = date.day().min(days_in_month(year, month));
+
+    NaiveDate::from_ymd(year, month, day)
+}
+
+/// Moves a date to the given day of its month, clamped to the end of the
+/// month. Negative days count back from the end of the month, so -1 is the
+/// last day.
+fn with_day(date: NaiveDate, day: i32) -> NaiveDate {
+    let last = days_in_month(date.year(), date.month()) as i32;
+    let day = if day > 0 {
+        day.min(last)
+    } else {
+        (last + 1 + day).max(1)
+    };
+
+    NaiveDate::from_ymd(date.year(), date.month(), day as u32)
+}
+
+fn days_in_month(year: i32, month: u32) -> u32 {
+    (28..=31)
+        .rev()
+        .find(|&day| NaiveDate::from_ymd_opt(year, month, day).is_some())
+        .unwrap_or(28)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd(y, m, d)
+    }
+
+    fn payment(merchant: &str, date: NaiveDate) -> Transaction {
+        Transaction {
+            id: format!("{}-{}", merchant, date),
+            account_id: "account".to_owned(),
+            timestamp: Utc.from_utc_date(&date).and_hms(9, 0, 0),
+            amount: Decimal::new(-999, 2),
+            currency: "GBP".to_owned(),
+            transaction_type: Some("DEBIT".to_owned()),
+            category: Some("PURCHASE".to_owned()),
+            description: None,
+            merchant_name: Some(merchant.to_owned()),
+            status: db::transactions::Status::Settled,
+        }
+    }
+
+    fn dates(payments: &[Payment]) -> Vec<NaiveDate> {
+        let mut dates = payments.iter().map(|p| p.date).collect::<Vec<_>>();
+        dates.sort();
+        dates
+    }
+
+    #[test]
+    fn add_months_clamps_to_month_end() {
+        assert_eq!(add_months(date(2020, 1, 31), 1), date(2020, 2, 29));
+        assert_eq!(add_months(date(2021, 1, 31), 1), date(2021, 2, 28));
+        assert_eq!(add_months(date(2020, 11, 30), 3), date(2021, 2, 28));
+        assert_eq!(add_months(date(2020, 8, 31), 1), date(2020, 9, 30));
+    }
+
+    #[test]
+    fn add_months_crosses_years() {
+        assert_eq!(add_months(date(2020, 12, 15), 1), date(2021, 1, 15));
+        assert_eq!(add_months(date(2020, 6, 1), 12), date(2021, 6, 1));
+        assert_eq!(add_months(date(2020, 6, 1), 30), date(2022, 12, 1));
+    }
+
+    #[test]
+    fn add_months_from_original_date_does_not_drift() {
+        // Direct debits are projected from the previous payment, rather than
+        // from the last projected date.
+        let previous = date(2020, 1, 31);
+        assert_eq!(add_months(previous, 1), date(2020, 2, 29));
+        assert_eq!(add_months(previous, 2), date(2020, 3, 31));
+    }
+
+    #[test]
+    fn next_standing_order_date_monthly() {
+        let next = next_standing_order_date("IntrvlMnthDay:01:15", date(2020, 1, 15));
+        assert_eq!(next, Some(date(2020, 2, 15)));
+
+        let next = next_standing_order_date("IntrvlMnthDay:03:15", date(2020, 11, 15));
+        assert_eq!(next, Some(date(2021, 2, 15)));
+    }
+
+    #[test]
+    fn next_standing_order_date_month_end() {
+        let feb = next_standing_order_date("IntrvlMnthDay:01:31", date(2020, 1, 31));
+        assert_eq!(feb, Some(date(2020, 2, 29)));
+
+        // Back to the 31st after a short month.
+        let mar = next_standing_order_date("IntrvlMnthDay:01:31", date(2020, 2, 29));
+        assert_eq!(mar, Some(date(2020, 3, 31)));
+
+        // Negative days count back from the end of the month.
+        let last = next_standing_order_date("IntrvlMnthDay:01:-01", date(2020, 3, 31));
+        assert_eq!(last, Some(date(2020, 4, 30)));
+    }
+
+    #[test]
+    fn next_standing_order_date_other_frequencies() {
+        // 2020-09-04 is a Friday.
+        let friday = date(2020, 9, 4);
+
+        assert_eq!(
+            next_standing_order_date("EvryDay", friday),
+            Some(date(2020, 9, 5))
+        );
+        assert_eq!(
+            next_standing_order_date("EvryWorkgDay", friday),
+            Some(date(2020, 9, 7))
+        );
+        assert_eq!(
+            next_standing_order_date("IntrvlWkDay:02:05", friday),
+            Some(date(2020, 9, 18))
+        );
+        assert_eq!(
+            next_standing_order_date("QtrDay:ENGLISH", date(2020, 11, 30)),
+            Some(date(2021, 2, 28))
+        );
+        assert_eq!(next_standing_order_date("Unknown", friday), None);
+    }
+
+    #[test]
+    fn recurring_payments_projects_monthly_payments() {
+        let history = vec![
+            payment("Netflix", date(2020, 6, 10)),
+            payment("Netflix", date(2020, 7, 10)),
+            payment("Netflix", date(2020, 8, 10)),
+        ];
+
+        let payments = recurring_payments(&history, date(2020, 8, 20), date(2020, 10, 31));
+
+        // The median gap is 31 days.
+        assert_eq!(
+            dates(&payments),
+            vec![date(2020, 9, 10), date(2020, 10, 11)]
+        );
+        assert!(payments
+            .iter()
+            .all(|p| p.amount == Some(Decimal::new(-999, 2))));
+        assert!(payments.iter().all(|p| p.source == Source::Recurring));
+    }
+
+    #[test]
+    fn recurring_payments_skips_lapsed_payments() {
+        let history = vec![
+            payment("Netflix", date(2020, 1, 10)),
+            payment("Netflix", date(2020, 2, 10)),
+            payment("Netflix", date(2020, 3, 10)),
+        ];
+
+        let payments = recurring_payments(&history, date(2020, 8, 20), date(2020, 10, 31));
+
+        assert!(payments.is_empty());
+    }
+
+    #[test]
+    fn recurring_payments_allows_a_late_payment() {
+        let history = vec![
+            payment("Netflix", date(2020, 6, 10)),
+            payment("Netflix", date(2020, 7, 10)),
+            payment("Netflix", date(2020, 8, 10)),
+        ];
+
+        // Due on the 10th, but a few days overdue is still within the
+        // tolerance, so it's expected straight away.
+        let payments = recurring_payments(&history, date(2020, 9, 13), date(2020, 9, 30));
+        assert_eq!(dates(&payments), vec![date(2020, 9, 13)]);
+
+        let payments = recurring_payments(&history, date(2020, 9, 13), date(2020, 10, 31));
+        assert_eq!(
+            dates(&payments),
+            vec![date(2020, 9, 13), date(2020, 10, 11)]
+        );
+    }
+
+    #[test]
+    fn recurring_payments_needs_regular_history() {
+        let too_few = vec![
+            payment("Gym", date(2020, 7, 1)),
+            payment("Gym", date(2020, 8, 1)),
+        ];
+        assert!(recurring_payments(&too_few, date(2020, 8, 10), date(2020, 12, 31)).is_empty());
+
+        let irregular = vec![
+            payment("Shop", date(2020, 6, 1)),
+            payment("Shop", date(2020, 6, 10)),
+            payment("Shop", date(2020, 8, 1)),
+        ];
+        assert!(recurring_payments(&irregular, date(2020, 8, 10), date(2020, 12, 31)).is_empty());
+    }
+}
@@ -1,40 +1,185 @@
+use anyhow::anyhow;
 use async_trait::async_trait;
-use chrono::{Duration, Utc};
-
-use crate::db::{self, Db};
+use chrono::{DateTime, Duration, Utc};
+
+use crate::db::{
+    self,
+    accounts::{Account, Kind},
+    providers::Status,
+    Db,
+};
+
+/// How long a consent lasts if TrueLayer doesn't say, which is the usual
+/// Open Banking limit.
+const DEFAULT_CONSENT_DAYS: i64 = 90;
+
+/// Works out when a consent was granted and when it expires from the token
+/// metadata, falling back to a default lifetime starting now.
+pub fn consent_period(metadata: &true_layer::TokenMetadata) -> (DateTime<Utc>, DateTime<Utc>) {
+    let granted_at = metadata.consent_created_at.unwrap_or_else(Utc::now);
+    let expires_at = metadata
+        .consent_expires_at
+        .unwrap_or_else(|| granted_at + Duration::days(DEFAULT_CONSENT_DAYS));
+
+    (granted_at, expires_at)
+}
 
 pub async fn save_credentials(
     db: &Db,
     true_layer: &true_layer::Client,
+    id: &str,
     token_res: true_layer::TokenResponse,
 ) -> anyhow::Result<String> {
     let metadata = true_layer.token_metadata(&token_res.access_token).await?;
     let expires_at = Utc::now() + Duration::seconds(token_res.expires_in);
 
-    let id = &metadata.provider.provider_id;
     let access_token = &token_res.access_token;
     let refresh_token = &token_res.refresh_token;
 
     db::providers::update_credentials(db, id, access_token, expires_at, refresh_token).await?;
 
+    // The consent is unchanged by a refresh, so only update it if TrueLayer
+    // told us about it.
+    if let (Some(granted_at), Some(expires_at)) =
+        (metadata.consent_created_at, metadata.consent_expires_at)
+    {
+        db::providers::update_consent(db, id, granted_at, expires_at).await?;
+    }
+
     Ok(token_res.access_token)
 }
 
+/// Renews the access token for a provider using its refresh token.
+///
+/// If the refresh token is rejected, the consent has expired or been revoked,
+/// so the provider is marked as needing to be re-authorised rather than
+/// retrying on every sync.
+async fn renew_credentials(
+    db: &Db,
+    true_layer: &true_layer::Client,
+    provider: &str,
+    refresh_token: &str,
+) -> anyhow::Result<String> {
+    let token_res = match true_layer.renew_token(refresh_token).await {
+        Ok(token_res) => token_res,
+        Err(e) => {
+            if true_layer::is_invalid_grant(&e) {
+                log::warn!("consent for provider '{}' is no longer valid", provider);
+                db::providers::set_status(db, provider, Status::ReauthRequired).await?;
+            }
+            return Err(e);
+        }
+    };
+
+    save_credentials(db, true_layer, provider, token_res).await
+}
+
+/// The outcome of reconciling a provider's saved accounts with TrueLayer.
+#[derive(Debug, Default)]
+pub struct AccountChanges {
+    /// The ids of all accounts returned by the provider.
+    pub accounts: Vec<String>,
+    pub added: Vec<String>,
+    pub closed: Vec<String>,
+}
+
+/// Fetches the accounts and cards for a provider from TrueLayer and saves
+/// them to the database.
+///
+/// Details of existing accounts (e.g. the display name) are refreshed, and
+/// any saved accounts that the provider no longer returns are flagged as
+/// closed.
 pub async fn fetch_provider_accounts(
     db: &Db,
     true_layer: &true_layer::Client,
     provider: &str,
-) -> anyhow::Result<()> {
-    let accounts = true_layer.accounts(&provider).await?;
+) -> anyhow::Result<AccountChanges> {
+    let mut accounts = true_layer
+        .accounts(provider)
+        .await?
+        .into_iter()
+        .map(|a| Account {
+            id: a.account_id,
+            provider_id: provider.to_owned(),
+            display_name: a.display_name,
+            kind: Kind::Account,
+            account_type: Some(a.account_type),
+            currency: Some(a.currency),
+            iban: a.account_number.iban,
+            sort_code: a.account_number.sort_code,
+            number: a.account_number.number,
+            archived_at: None,
+            closed_at: None,
+        })
+        .collect::<Vec<_>>();
+
+    // Not every provider supports cards, so don't fail if they can't be
+    // fetched. Only the kinds that were fetched are checked for closed
+    // accounts, so a failure doesn't close every card.
+    let mut fetched = vec![Kind::Account];
+
+    match true_layer.cards(provider).await {
+        Ok(cards) => {
+            fetched.push(Kind::Card);
+            accounts.extend(cards.into_iter().map(|c| Account {
+                id: c.account_id,
+                provider_id: provider.to_owned(),
+                display_name: c.display_name,
+                kind: Kind::Card,
+                account_type: Some(c.card_type),
+                currency: Some(c.currency),
+                iban: None,
+                sort_code: None,
+                number: Some(c.partial_card_number),
+                archived_at: None,
+                closed_at: None,
+            }))
+        }
+        Err(e) => log::warn!("failed to get cards for provider '{}': {}", provider, e),
+    }
+
+    let mut changes = AccountChanges::default();
 
     for account in accounts {
-        let id = &account.account_id;
-        let name = &account.display_name;
-        let created = db::accounts::insert(db, id, &provider, name).await?;
+        let created = db::accounts::upsert(db, &account).await?;
         if created {
             log::info!("new account '{}' added to db", account.display_name);
+            changes.added.push(account.id.clone());
         } else {
-            log::info!("account '{}' already exists", account.display_name);
+            log::info!("account '{}' updated", account.display_name);
+        }
+        changes.accounts.push(account.id);
+    }
+
+    changes.closed = db::accounts::close_missing(db, provider, &fetched, &changes.accounts).await?;
+
+    for id in &changes.closed {
+        log::info!("account '{}' is no longer returned, marked closed", id);
+    }
+
+    Ok(changes)
+}
+
+/// Renews the access tokens of any providers that will expire within the
+/// given duration, so that they are ready before they are next needed.
+pub async fn refresh_tokens(
+    db: &Db,
+    true_layer: &true_layer::Client,
+    within: Duration,
+) -> anyhow::Result<()> {
+    for provider in db::providers::expiring_before(db, Utc::now() + within).await? {
+        let res = async {
+            let (_, _, refresh_token) = db::providers::credentials(db, &provider).await?;
+            renew_credentials(db, true_layer, &provider, &refresh_token).await
+        };
+
+        match res.await {
+            Ok(_) => log::info!("renewed access token for provider '{}'", provider),
+            Err(e) => log::error!(
+                "failed to renew access token for provider '{}': {:#}",
+                provider,
+                e
+            ),
         }
     }
 
@@ -56,6 +201,19 @@ impl true_layer::AuthProvider for AuthProvider {
         true_layer: &true_layer::Client,
         provider_id: &str,
     ) -> anyhow::Result<String> {
+        match db::providers::status(&self.0, provider_id).await? {
+            Status::Connected => {}
+            Status::Disconnected => {
+                return Err(anyhow!("provider '{}' has been disconnected", provider_id))
+            }
+            Status::ReauthRequired => {
+                return Err(anyhow!(
+                    "provider '{}' needs to be re-authorised",
+                    provider_id
+                ))
+            }
+        }
+
         let (access_token, expires_at, refresh_token) =
             db::providers::credentials(&self.0, provider_id).await?;
 
@@ -63,10 +221,7 @@ impl true_layer::AuthProvider for AuthProvider {
             return Ok(access_token);
         }
 
-        let token_res = true_layer.renew_token(&refresh_token).await?;
-        let access_token = save_credentials(&self.0, true_layer, token_res).await?;
-
-        Ok(access_token)
+        renew_credentials(&self.0, true_layer, provider_id, &refresh_token).await
     }
 
     async fn token_for_account(
@@ -74,16 +229,11 @@ impl true_layer::AuthProvider for AuthProvider {
         true_layer: &true_layer::Client,
         account_id: &str,
     ) -> anyhow::Result<String> {
-        let (access_token, expires_at, refresh_token) =
-            db::accounts::credentials(&self.0, account_id).await?;
-
-        if expires_at > Utc::now() {
-            return Ok(access_token);
-        }
-
-        let token_res = true_layer.renew_token(&refresh_token).await?;
-        let access_token = save_credentials(&self.0, true_layer, token_res).await?;
+        let account = db::accounts::get(&self.0, account_id)
+            .await?
+            .ok_or_else(|| anyhow!("account '{}' not found", account_id))?;
 
-        Ok(access_token)
+        self.token_for_provider(true_layer, &account.provider_id)
+            .await
     }
 }
@@ -0,0 +1,382 @@
+//! Tests that need a database. They are ignored by default; to run them, set
+//! `FINTRACK_TEST_DATABASE_URL` to a database that can be migrated and run
+//! `cargo test -- --ignored`.
+
+use std::env;
+use std::future::Future;
+use std::sync::Once;
+
+use actix_web::rt::System;
+use chrono::{Duration, TimeZone, Utc};
+use fintrack::crypto::Cipher;
+use fintrack::db::{
+    self,
+    accounts::{Account, Kind},
+    providers::{Provider, Upserted},
+    transactions::{Filter, Status, Transaction},
+    Db,
+};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use sqlx::postgres::PgRow;
+use sqlx::Row;
+
+const KEY: &[u8] = b"fintrack test key";
+const OLD_KEY: &[u8] = b"fintrack old test key";
+
+static MIGRATIONS: Once = Once::new();
+
+fn database_url() -> String {
+    env::var("FINTRACK_TEST_DATABASE_URL").expect("FINTRACK_TEST_DATABASE_URL must be set")
+}
+
+/// Runs a test against a migrated database.
+fn run<F, R>(test: F)
+where
+    F: FnOnce(Db) -> R + 'static,
+    R: Future<Output = anyhow::Result<()>> + 'static,
+{
+    MIGRATIONS.call_once(|| {
+        System::new("migrations").block_on(async {
+            let db = Db::connect(&database_url(), KEY).await.unwrap();
+            fintrack::migrations::run(&db).await.unwrap();
+            db.close().await;
+        })
+    });
+
+    System::new("test").block_on(async {
+        let db = Db::connect(&database_url(), KEY).await.unwrap();
+        test(db.clone()).await.unwrap();
+        db.close().await;
+    });
+}
+
+fn random_id(prefix: &str) -> String {
+    let suffix = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(12)
+        .collect::<String>();
+
+    format!("{}-{}", prefix, suffix)
+}
+
+async fn create_user(db: &Db) -> anyhow::Result<i32> {
+    db::users::insert(db, &random_id("user"), "not a real hash").await
+}
+
+fn provider(user_id: i32, truelayer_id: &str) -> Provider {
+    Provider {
+        truelayer_id: truelayer_id.to_owned(),
+        user_id,
+        display_name: "Test Bank".to_owned(),
+        logo_url: "https://example.com/logo.svg".to_owned(),
+        refresh_token: format!("refresh {}", truelayer_id),
+        access_token: format!("access {}", truelayer_id),
+        expires_at: Utc::now() + Duration::hours(1),
+        consent_granted_at: Utc::now(),
+        consent_expires_at: Utc::now() + Duration::days(90),
+    }
+}
+
+fn account(provider_id: &str, id: &str) -> Account {
+    Account {
+        id: id.to_owned(),
+        provider_id: provider_id.to_owned(),
+        display_name: "Current Account".to_owned(),
+        kind: Kind::Account,
+        account_type: None,
+        currency: None,
+        iban: None,
+        sort_code: None,
+        number: None,
+        archived_at: None,
+        closed_at: None,
+    }
+}
+
+async fn saved_tokens(db: &Db, id: &str) -> anyhow::Result<(String, String)> {
+    let tokens = sqlx::query("SELECT access_token, refresh_token FROM providers WHERE id = $1")
+        .bind(id)
+        .try_map(|row: PgRow| Ok((row.get(0), row.get(1))))
+        .fetch_one(db.pool())
+        .await?;
+
+    Ok(tokens)
+}
+
+#[test]
+#[ignore]
+fn tokens_are_encrypted() {
+    run(|db| async move {
+        let user = create_user(&db).await?;
+        let truelayer_id = random_id("bank");
+        let (id, _) = db::providers::upsert(&db, &provider(user, &truelayer_id)).await?;
+
+        let (access_token, refresh_token) = saved_tokens(&db, &id).await?;
+        assert!(Cipher::is_current(&access_token));
+        assert!(Cipher::is_current(&refresh_token));
+        assert!(!access_token.contains(&truelayer_id));
+
+        let (access_token, _, refresh_token) = db::providers::credentials(&db, &id).await?;
+        assert_eq!(access_token, format!("access {}", truelayer_id));
+        assert_eq!(refresh_token, format!("refresh {}", truelayer_id));
+
+        db::providers::delete(&db, &id).await
+    });
+}
+
+#[test]
+#[ignore]
+fn providers_can_be_connected_by_several_users() {
+    run(|db| async move {
+        let truelayer_id = random_id("bank");
+        let alice = create_user(&db).await?;
+        let bob = create_user(&db).await?;
+
+        let (a, upserted) = db::providers::upsert(&db, &provider(alice, &truelayer_id)).await?;
+        assert_eq!(upserted, Upserted::Created);
+
+        let (b, upserted) = db::providers::upsert(&db, &provider(bob, &truelayer_id)).await?;
+        assert_eq!(upserted, Upserted::Created);
+        assert_ne!(a, b);
+
+        let mut reconnected = provider(alice, &truelayer_id);
+        reconnected.access_token = "new access token".to_owned();
+        let (id, upserted) = db::providers::upsert(&db, &reconnected).await?;
+        assert_eq!((id.as_str(), upserted), (a.as_str(), Upserted::Updated));
+
+        let (access_token, _, _) = db::providers::credentials(&db, &a).await?;
+        assert_eq!(access_token, "new access token");
+        let (access_token, _, _) = db::providers::credentials(&db, &b).await?;
+        assert_eq!(access_token, format!("access {}", truelayer_id));
+
+        assert!(db::providers::owned_by(&db, &a, alice).await?);
+        assert!(!db::providers::owned_by(&db, &a, bob).await?);
+
+        db::providers::delete(&db, &a).await?;
+        db::providers::delete(&db, &b).await
+    });
+}
+
+#[test]
+#[ignore]
+fn accounts_stay_with_their_provider() {
+    run(|db| async move {
+        let user = create_user(&db).await?;
+        let (a, _) = db::providers::upsert(&db, &provider(user, &random_id("bank"))).await?;
+        let (b, _) = db::providers::upsert(&db, &provider(user, &random_id("bank"))).await?;
+
+        let id = random_id("account");
+        assert!(db::accounts::upsert(&db, &account(&a, &id)).await?);
+        assert!(!db::accounts::upsert(&db, &account(&a, &id)).await?);
+        assert!(db::accounts::upsert(&db, &account(&b, &id)).await.is_err());
+        assert_eq!(
+            db::accounts::providers_of(&db, &[id]).await?,
+            vec![a.clone()]
+        );
+
+        db::providers::delete(&db, &a).await?;
+        db::providers::delete(&db, &b).await
+    });
+}
+
+#[test]
+#[ignore]
+fn refreshing_accounts_keeps_them_archived() {
+    run(|db| async move {
+        let user = create_user(&db).await?;
+        let (provider_id, _) =
+            db::providers::upsert(&db, &provider(user, &random_id("bank"))).await?;
+
+        let id = random_id("account");
+        db::accounts::upsert(&db, &account(&provider_id, &id)).await?;
+        db::providers::disconnect(&db, &provider_id).await?;
+
+        let mut refreshed = account(&provider_id, &id);
+        refreshed.display_name = "Renamed Account".to_owned();
+        db::accounts::upsert(&db, &refreshed).await?;
+
+        let saved = db::accounts::get(&db, &id).await?.unwrap();
+        assert_eq!(saved.display_name, "Renamed Account");
+        assert!(saved.archived_at.is_some());
+
+        assert_eq!(
+            db::accounts::restore(&db, std::slice::from_ref(&id)).await?,
+            1
+        );
+        assert!(db::accounts::get(&db, &id)
+            .await?
+            .unwrap()
+            .archived_at
+            .is_none());
+        assert_eq!(db::accounts::restore(&db, &[id]).await?, 0);
+
+        db::providers::delete(&db, &provider_id).await
+    });
+}
+
+#[test]
+#[ignore]
+fn swapped_tokens_are_rejected() {
+    run(|db| async move {
+        let user = create_user(&db).await?;
+        let (a, _) = db::providers::upsert(&db, &provider(user, &random_id("bank"))).await?;
+        let (b, _) = db::providers::upsert(&db, &provider(user, &random_id("bank"))).await?;
+
+        // Copy b's token into a, and a's refresh token into its access token.
+        let (b_access_token, _) = saved_tokens(&db, &b).await?;
+        sqlx::query("UPDATE providers SET access_token = $1 WHERE id = $2")
+            .bind(&b_access_token)
+            .bind(&a)
+            .execute(db.pool())
+            .await?;
+        assert!(db::providers::credentials(&db, &a).await.is_err());
+
+        sqlx::query("UPDATE providers SET access_token = refresh_token WHERE id = $1")
+            .bind(&b)
+            .execute(db.pool())
+            .await?;
+        assert!(db::providers::credentials(&db, &b).await.is_err());
+
+        db::providers::delete(&db, &a).await?;
+        db::providers::delete(&db, &b).await
+    });
+}
+
+#[test]
+#[ignore]
+fn plaintext_tokens_are_upgraded_when_read() {
+    run(|db| async move {
+        let user = create_user(&db).await?;
+        let truelayer_id = random_id("bank");
+        let (id, _) = db::providers::upsert(&db, &provider(user, &truelayer_id)).await?;
+
+        sqlx::query(
+            "UPDATE providers SET access_token = 'access', refresh_token = 'refresh' WHERE id = $1",
+        )
+        .bind(&id)
+        .execute(db.pool())
+        .await?;
+
+        let (access_token, _, refresh_token) = db::providers::credentials(&db, &id).await?;
+        assert_eq!(access_token, "access");
+        assert_eq!(refresh_token, "refresh");
+
+        let (access_token, refresh_token) = saved_tokens(&db, &id).await?;
+        assert!(Cipher::is_current(&access_token));
+        assert!(Cipher::is_current(&refresh_token));
+
+        db::providers::delete(&db, &id).await
+    });
+}
+
+#[test]
+#[ignore]
+fn rotate_key_re_encrypts_tokens() {
+    run(|db| async move {
+        let old_db = Db::connect(&database_url(), OLD_KEY).await?;
+
+        let user = create_user(&db).await?;
+        let truelayer_id = random_id("bank");
+        let (id, _) = db::providers::upsert(&old_db, &provider(user, &truelayer_id)).await?;
+
+        assert!(db::providers::credentials(&db, &id).await.is_err());
+
+        let count = db::providers::rotate_key(&db, &Cipher::new(OLD_KEY)).await?;
+        assert!(count >= 1);
+
+        let (access_token, _, refresh_token) = db::providers::credentials(&db, &id).await?;
+        assert_eq!(access_token, format!("access {}", truelayer_id));
+        assert_eq!(refresh_token, format!("refresh {}", truelayer_id));
+        assert!(db::providers::credentials(&old_db, &id).await.is_err());
+
+        // Running it again (e.g. after an interruption) is harmless.
+        db::providers::rotate_key(&db, &Cipher::new(OLD_KEY)).await?;
+        assert!(db::providers::credentials(&db, &id).await.is_ok());
+
+        old_db.close().await;
+        db::providers::delete(&db, &id).await
+    });
+}
+
+#[test]
+#[ignore]
+fn search_ranks_transactions() {
+    run(|db| async move {
+        let user = create_user(&db).await?;
+        let (provider_id, _) =
+            db::providers::upsert(&db, &provider(user, &random_id("bank"))).await?;
+
+        let account_id = random_id("account");
+        db::accounts::upsert(&db, &account(&provider_id, &account_id)).await?;
+
+        let transactions = [
+            ("Amazon", "AMAZON.CO.UK REFUND"),
+            ("Amazon", "AMAZON.CO.UK*AB12CD"),
+            ("Sainsbury's", "SAINSBURYS S/MKT"),
+            ("Tesco", "TESCO STORES 1234"),
+            ("Marks & Spencer", "M&S <SIMPLY FOOD>"),
+        ]
+        .iter()
+        .enumerate()
+        .map(|(i, (merchant, description))| Transaction {
+            id: random_id("transaction"),
+            account_id: account_id.clone(),
+            timestamp: Utc.ymd(2020, 4, 1 + i as u32).and_hms(12, 0, 0),
+            amount: (-10 * (i as i64 + 1)).into(),
+            currency: "GBP".to_owned(),
+            transaction_type: None,
+            category: None,
+            description: Some(description.to_string()),
+            merchant_name: Some(merchant.to_string()),
+            status: Status::Settled,
+        })
+        .collect::<Vec<_>>();
+
+        db::transactions::upsert_after(
+            &db,
+            &account_id,
+            Utc.ymd(2020, 4, 1).and_hms(0, 0, 0),
+            &transactions,
+            &[],
+        )
+        .await?;
+
+        let search = |text: &'static str| {
+            let db = db.clone();
+            async move {
+                db::transactions::search(&db, user, text, &Filter::default(), 10)
+                    .await
+                    .map(|matches| {
+                        matches
+                            .into_iter()
+                            .map(|m| (m.entry.transaction.description.unwrap(), m.snippet))
+                            .collect::<Vec<_>>()
+                    })
+            }
+        };
+
+        // Any of the words can match, and matching more ranks higher.
+        let matches = search("find that Amazon refund from last spring").await?;
+        let descriptions = matches.iter().map(|(d, _)| d.as_str()).collect::<Vec<_>>();
+        assert_eq!(descriptions, ["AMAZON.CO.UK REFUND", "AMAZON.CO.UK*AB12CD"]);
+        assert_eq!(
+            matches[0].1,
+            "\u{2}Amazon\u{3} - AMAZON.CO.UK \u{2}REFUND\u{3}"
+        );
+
+        // Misspellings are found by word similarity.
+        let matches = search("sainsbry").await?;
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].0, "SAINSBURYS S/MKT");
+
+        let matches = search("spencer").await?;
+        assert_eq!(matches.len(), 1);
+        // Snippets aren't escaped.
+        assert!(matches[0].1.starts_with("Marks & \u{2}Spencer\u{3} - M&S"));
+
+        assert!(search("waitrose").await?.is_empty());
+
+        db::providers::delete(&db, &provider_id).await
+    });
+}
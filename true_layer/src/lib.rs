@@ -4,10 +4,11 @@ use std::fmt::{self, Display};
 use anyhow::anyhow;
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
-use reqwest::{header, StatusCode};
+use reqwest::{header, StatusCode, Url};
 use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::Value;
+use sha2::{Digest, Sha256};
 
 #[async_trait]
 pub trait AuthProvider {
@@ -24,6 +25,18 @@ pub trait AuthProvider {
     ) -> anyhow::Result<String>;
 }
 
+/// Optional parameters for [`Client::auth_link`].
+#[derive(Default)]
+pub struct AuthLinkOptions<'a> {
+    /// Skips the provider selection, e.g. when re-authorising an existing
+    /// connection.
+    pub provider: Option<&'a str>,
+    /// Passed back unchanged to the callback, to protect against CSRF.
+    pub state: Option<&'a str>,
+    /// A PKCE code verifier, whose S256 challenge is included in the link.
+    pub code_verifier: Option<&'a str>,
+}
+
 pub enum Env {
     Sandbox,
     Live,
@@ -59,8 +72,8 @@ impl TrueLayerConfig {
 
 #[derive(Debug, Deserialize)]
 pub struct ErrorResponse {
-    error: String,
-    error_description: Option<String>,
+    pub error: String,
+    pub error_description: Option<String>,
 }
 
 impl Display for ErrorResponse {
@@ -69,6 +82,16 @@ impl Display for ErrorResponse {
     }
 }
 
+impl std::error::Error for ErrorResponse {}
+
+/// Returns true if the error is an `invalid_grant` from the auth server, which
+/// is what a token refresh fails with once the consent has expired or been
+/// revoked.
+pub fn is_invalid_grant(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<ErrorResponse>()
+        .map_or(false, |e| e.error == "invalid_grant")
+}
+
 #[derive(Debug, Deserialize)]
 pub struct TokenResponse {
     pub access_token: String,
@@ -92,6 +115,10 @@ struct Results<T> {
 #[derive(Debug, Deserialize)]
 pub struct TokenMetadata {
     pub provider: ProviderMetadata,
+    #[serde(default)]
+    pub consent_created_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub consent_expires_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -120,15 +147,97 @@ pub struct AccountNumber {
     pub sort_code: Option<String>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Info {
+    pub full_name: String,
+    pub date_of_birth: Option<String>,
+    #[serde(default)]
+    pub emails: Vec<String>,
+    #[serde(default)]
+    pub phones: Vec<String>,
+    #[serde(default)]
+    pub addresses: Vec<Address>,
+    pub update_timestamp: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Address {
+    pub address: Option<String>,
+    pub city: Option<String>,
+    pub state: Option<String>,
+    pub zip: Option<String>,
+    pub country: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AccountBalance {
     pub currency: String,
-    pub available: f64,
-    pub current: f64,
-    pub overdraft: f64,
+    pub available: Decimal,
+    pub current: Decimal,
+    #[serde(default)]
+    pub overdraft: Option<Decimal>,
+    pub update_timestamp: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Card {
+    pub account_id: String,
+    pub card_network: String,
+    pub card_type: String,
+    pub currency: String,
+    pub display_name: String,
+    pub partial_card_number: String,
+    pub name_on_card: Option<String>,
+    pub valid_from: Option<String>,
+    pub valid_to: Option<String>,
+    pub update_timestamp: String,
+    pub provider: ProviderMetadata,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CardBalance {
+    pub currency: String,
+    pub available: Decimal,
+    pub current: Decimal,
+    pub credit_limit: Option<Decimal>,
+    pub last_statement_balance: Option<Decimal>,
+    pub last_statement_date: Option<String>,
+    pub payment_due: Option<Decimal>,
+    pub payment_due_date: Option<String>,
     pub update_timestamp: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DirectDebit {
+    pub direct_debit_id: String,
+    pub timestamp: DateTime<Utc>,
+    pub name: String,
+    pub status: String,
+    pub previous_payment_timestamp: Option<DateTime<Utc>>,
+    pub previous_payment_amount: Option<Decimal>,
+    pub currency: String,
+    #[serde(default)]
+    pub meta: Value,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StandingOrder {
+    pub frequency: String,
+    pub status: Option<String>,
+    pub timestamp: DateTime<Utc>,
+    pub currency: String,
+    #[serde(default)]
+    pub meta: Value,
+    pub next_payment_date: Option<DateTime<Utc>>,
+    pub next_payment_amount: Option<Decimal>,
+    pub first_payment_date: Option<DateTime<Utc>>,
+    pub first_payment_amount: Option<Decimal>,
+    pub final_payment_date: Option<DateTime<Utc>>,
+    pub final_payment_amount: Option<Decimal>,
+    pub reference: Option<String>,
+    pub payee: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Transaction {
     pub transaction_id: String,
@@ -140,8 +249,11 @@ pub struct Transaction {
     pub merchant_name: Option<String>,
     pub amount: Decimal,
     pub currency: String,
+    #[serde(default)]
     pub meta: Value,
-    pub running_balance: TransactionRunningBalance,
+    /// Not included for pending transactions.
+    #[serde(default)]
+    pub running_balance: Option<TransactionRunningBalance>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -166,40 +278,72 @@ impl Client {
         }
     }
 
-    pub fn auth_link(&self, callback: &str) -> String {
+    /// Builds the link for connecting a bank.
+    pub fn auth_link(&self, callback: &str, options: &AuthLinkOptions<'_>) -> String {
         let providers = match self.config.env {
             Env::Sandbox => "uk-ob-all%20uk-oauth-all%20uk-cs-mock",
             Env::Live => "uk-ob-all%20uk-oauth-all",
         };
 
-        format!(
+        let link = format!(
             "https://auth.{}/?\
                 response_type=code&\
                 client_id={}&\
                 scope=info%20accounts%20balance%20cards%20transactions%20direct_debits%20standing_orders%20offline_access&\
-                redirect_uri={}&\
                 providers={}",
             self.hostname(),
             self.config.client_id,
-            callback,
             providers,
-        )
+        );
+
+        let mut link = Url::parse(&link).expect("auth link should be a valid url");
+
+        // Anything else may come from the user, so needs encoding.
+        {
+            let mut query = link.query_pairs_mut();
+
+            query.append_pair("redirect_uri", callback);
+
+            if let Some(provider) = options.provider {
+                query.append_pair("provider_id", provider);
+            }
+
+            if let Some(state) = options.state {
+                query.append_pair("state", state);
+            }
+
+            if let Some(code_verifier) = options.code_verifier {
+                query.append_pair("code_challenge_method", "S256");
+                query.append_pair("code_challenge", &code_challenge(code_verifier));
+            }
+        }
+
+        link.to_string()
     }
 
-    pub async fn exchange_code(&self, code: &str, callback: &str) -> anyhow::Result<TokenResponse> {
+    /// Exchanges an authorization code for tokens. If the auth link was built
+    /// with a PKCE `code_verifier`, the same one must be passed here.
+    pub async fn exchange_code(
+        &self,
+        code: &str,
+        callback: &str,
+        code_verifier: Option<&str>,
+    ) -> anyhow::Result<TokenResponse> {
         let url = format!("https://auth.{}/connect/token", self.hostname());
-        let res = self
-            .client
-            .post(&url)
-            .form(&serde_json::json!({
-                "client_id": self.config.client_id,
-                "client_secret": self.config.client_secret,
-                "code": code,
-                "grant_type": "authorization_code",
-                "redirect_uri": callback,
-            }))
-            .send()
-            .await?;
+
+        let mut form = serde_json::json!({
+            "client_id": self.config.client_id,
+            "client_secret": self.config.client_secret,
+            "code": code,
+            "grant_type": "authorization_code",
+            "redirect_uri": callback,
+        });
+
+        if let Some(code_verifier) = code_verifier {
+            form["code_verifier"] = code_verifier.into();
+        }
+
+        let res = self.client.post(&url).form(&form).send().await?;
 
         if res.status() != StatusCode::OK {
             return tl_error(res).await;
@@ -229,6 +373,35 @@ impl Client {
         Ok(res.json().await?)
     }
 
+    /// Revokes the access for a provider, removing the connection on
+    /// TrueLayer's side.
+    pub async fn revoke(&self, provider: &str) -> anyhow::Result<()> {
+        let access_token = self
+            .auth_provider
+            .token_for_provider(&self, provider)
+            .await?;
+
+        self.revoke_token(&access_token).await
+    }
+
+    /// Revokes the access granted to an access token, e.g. one that was just
+    /// issued but couldn't be saved.
+    pub async fn revoke_token(&self, access_token: &str) -> anyhow::Result<()> {
+        let url = format!("https://auth.{}/api/delete", self.hostname());
+        let res = self
+            .client
+            .delete(&url)
+            .header(header::AUTHORIZATION, format!("Bearer {}", access_token))
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return tl_error(res).await;
+        }
+
+        Ok(())
+    }
+
     pub async fn token_metadata(&self, access_token: &str) -> anyhow::Result<TokenMetadata> {
         let url = format!("https://api.{}/data/v1/me", self.hostname());
         let res = self
@@ -256,6 +429,18 @@ impl Client {
         Ok(self.client.get(&url).send().await?.json().await?)
     }
 
+    /// Gets the identity of the account holder(s) for a provider.
+    pub async fn info(&self, provider: &str) -> anyhow::Result<Vec<Info>> {
+        let access_token = self
+            .auth_provider
+            .token_for_provider(&self, provider)
+            .await?;
+
+        let url = format!("https://api.{}/data/v1/info", self.hostname());
+
+        self.get_results(&url, &access_token).await
+    }
+
     pub async fn accounts(&self, provider: &str) -> anyhow::Result<Vec<Account>> {
         let access_token = self
             .auth_provider
@@ -353,6 +538,113 @@ impl Client {
 
         Ok(res.json::<Results<_>>().await?.results)
     }
+
+    pub async fn direct_debits(&self, account: &str) -> anyhow::Result<Vec<DirectDebit>> {
+        let access_token = self.auth_provider.token_for_account(&self, account).await?;
+        let url = format!(
+            "https://api.{}/data/v1/accounts/{}/direct_debits",
+            self.hostname(),
+            account
+        );
+
+        self.get_results(&url, &access_token).await
+    }
+
+    pub async fn standing_orders(&self, account: &str) -> anyhow::Result<Vec<StandingOrder>> {
+        let access_token = self.auth_provider.token_for_account(&self, account).await?;
+        let url = format!(
+            "https://api.{}/data/v1/accounts/{}/standing_orders",
+            self.hostname(),
+            account
+        );
+
+        self.get_results(&url, &access_token).await
+    }
+
+    pub async fn cards(&self, provider: &str) -> anyhow::Result<Vec<Card>> {
+        let access_token = self
+            .auth_provider
+            .token_for_provider(&self, provider)
+            .await?;
+
+        let url = format!("https://api.{}/data/v1/cards", self.hostname());
+
+        self.get_results(&url, &access_token).await
+    }
+
+    pub async fn card_balance(&self, card: &str) -> anyhow::Result<CardBalance> {
+        let access_token = self.auth_provider.token_for_account(&self, card).await?;
+        let url = format!(
+            "https://api.{}/data/v1/cards/{}/balance",
+            self.hostname(),
+            card
+        );
+
+        Ok(self
+            .get_results(&url, &access_token)
+            .await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("invalid card balance response"))?)
+    }
+
+    pub async fn card_transactions(
+        &self,
+        card: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> anyhow::Result<Vec<Transaction>> {
+        let access_token = self.auth_provider.token_for_account(&self, card).await?;
+        let url = format!(
+            "https://api.{}/data/v1/cards/{}/transactions?from={}&to={}",
+            self.hostname(),
+            card,
+            from.format("%FT%TZ"),
+            to.format("%FT%TZ")
+        );
+
+        self.get_results(&url, &access_token).await
+    }
+
+    pub async fn card_pending_transactions(&self, card: &str) -> anyhow::Result<Vec<Transaction>> {
+        let access_token = self.auth_provider.token_for_account(&self, card).await?;
+        let url = format!(
+            "https://api.{}/data/v1/cards/{}/transactions/pending",
+            self.hostname(),
+            card
+        );
+
+        self.get_results(&url, &access_token).await
+    }
+
+    /// Sends an authenticated GET request to a Data API endpoint and returns
+    /// the `results` from the response.
+    async fn get_results<T: DeserializeOwned>(
+        &self,
+        url: &str,
+        access_token: &str,
+    ) -> anyhow::Result<Vec<T>> {
+        let res = self
+            .client
+            .get(url)
+            .header(header::AUTHORIZATION, format!("Bearer {}", access_token))
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return tl_error(res).await;
+        }
+
+        Ok(res.json::<Results<_>>().await?.results)
+    }
+}
+
+/// Derives a PKCE code challenge from a code verifier, using the S256 method.
+fn code_challenge(code_verifier: &str) -> String {
+    base64::encode_config(
+        Sha256::digest(code_verifier.as_bytes()),
+        base64::URL_SAFE_NO_PAD,
+    )
 }
 
 async fn tl_error<T>(res: reqwest::Response) -> anyhow::Result<T> {
@@ -360,7 +652,7 @@ async fn tl_error<T>(res: reqwest::Response) -> anyhow::Result<T> {
 
     if status.is_client_error() {
         let res: ErrorResponse = res.json().await?;
-        return Err(anyhow!(res));
+        return Err(res.into());
     }
 
     Err(anyhow!(
@@ -368,3 +660,65 @@ async fn tl_error<T>(res: reqwest::Response) -> anyhow::Result<T> {
         status
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NoAuth;
+
+    #[async_trait]
+    impl AuthProvider for NoAuth {
+        async fn token_for_provider(&self, _: &Client, _: &str) -> anyhow::Result<String> {
+            Err(anyhow!("not supported"))
+        }
+
+        async fn token_for_account(&self, _: &Client, _: &str) -> anyhow::Result<String> {
+            Err(anyhow!("not supported"))
+        }
+    }
+
+    fn client() -> Client {
+        Client {
+            client: reqwest::Client::new(),
+            config: TrueLayerConfig {
+                client_id: "client".to_owned(),
+                client_secret: "secret".to_owned(),
+                env: Env::Sandbox,
+            },
+            auth_provider: Box::new(NoAuth),
+        }
+    }
+
+    #[test]
+    fn auth_link_encodes_parameters() {
+        let link = client().auth_link(
+            "http://localhost/connect/callback",
+            &AuthLinkOptions {
+                provider: Some("ob-bank&client_id=evil #x"),
+                state: Some("a.b.c"),
+                code_verifier: None,
+            },
+        );
+
+        let url = Url::parse(&link).unwrap();
+        let pairs = url.query_pairs().into_owned().collect::<Vec<_>>();
+        let get = |name: &str| {
+            pairs
+                .iter()
+                .filter(|(k, _)| k == name)
+                .map(|(_, v)| v.as_str())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(url.fragment(), None);
+        assert_eq!(get("client_id"), vec!["client"]);
+        assert_eq!(get("provider_id"), vec!["ob-bank&client_id=evil #x"]);
+        assert_eq!(get("state"), vec!["a.b.c"]);
+        assert_eq!(
+            get("redirect_uri"),
+            vec!["http://localhost/connect/callback"]
+        );
+        assert_eq!(get("providers"), vec!["uk-ob-all uk-oauth-all uk-cs-mock"]);
+    }
+}